@@ -0,0 +1,61 @@
+use crate::config::{Config, Mapping, Protocol};
+use clap::Parser;
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
+
+/// Forwards ports on the local UPnP router to this device and keeps the
+/// mappings alive until the program is closed.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    /// Config file to use. Defaults to `config.toml` in the current directory.
+    #[arg(long, value_name = "PATH", env = "UPNP_ENGAGE_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Forward only this device port, ignoring the mappings in the config file.
+    /// No config file is needed.
+    #[arg(long, value_name = "PORT", value_parser = clap::value_parser!(u16).range(1..))]
+    pub device_port: Option<u16>,
+
+    /// Router port for `--device-port`. Defaults to the device port.
+    #[arg(long, value_name = "PORT", requires = "device_port")]
+    pub router_port: Option<u16>,
+
+    /// Protocol to forward. Applies to every mapping.
+    #[arg(long, value_enum)]
+    pub protocol: Option<Protocol>,
+
+    /// Never wait for input. An incomplete config is reported as an error
+    /// instead. Implied when stdin is not a terminal.
+    #[arg(long)]
+    pub non_interactive: bool,
+}
+
+impl Cli {
+    /// Whether the command line describes the mapping on its own, so the
+    /// config file is optional.
+    pub fn is_one_off(&self) -> bool {
+        self.device_port.is_some()
+    }
+
+    /// Whether the program may stop and wait for the user.
+    pub fn is_interactive(&self) -> bool {
+        !self.non_interactive && io::stdin().is_terminal()
+    }
+
+    /// Overrides the config values that were given on the command line.
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(device_port) = self.device_port {
+            config.mappings = vec![Mapping {
+                device_port,
+                router_port: self.router_port.unwrap_or(0),
+                ..Default::default()
+            }];
+        }
+        if let Some(protocol) = self.protocol {
+            for mapping in &mut config.mappings {
+                mapping.protocol = protocol;
+            }
+        }
+    }
+}
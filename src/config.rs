@@ -1,63 +1,666 @@
 // src/config.rs
+use crate::pinhole::MAX_PINHOLE_LEASE;
+use igd::{PortMappingProtocol, SearchOptions};
 use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt;
 use std::fs;
 use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::Path;
 use std::process;
+use std::str::FromStr;
+use std::time::Duration;
 
-#[derive(Serialize, Deserialize, Debug)]
+pub const DEFAULT_LEASE_TIME: u32 = 3600;
+pub const DEFAULT_RENEWAL_INTERVAL: u32 = 3000;
+pub const DEFAULT_RETRY_INITIAL_DELAY: u32 = 5;
+pub const DEFAULT_RETRY_MAX_DELAY: u32 = 120;
+pub const DEFAULT_RETRY_DEADLINE: u32 = 600;
+pub const DEFAULT_DISCOVERY_TIMEOUT: u32 = 10;
+
+/// Where SSDP discovery requests are sent unless `broadcast_address` says
+/// otherwise.
+const SSDP_PORT: u16 = 1900;
+
+/// Prefix of the environment variables that override config values. The rest
+/// of the name is the upper-cased field, e.g. `UPNP_ENGAGE_LEASE_TIME`.
+const ENV_PREFIX: &str = "UPNP_ENGAGE_";
+
+const CONFIG_HEADER: &str = "\
+# lease_time is the lease requested from the router, in seconds. 0 requests a permanent lease.\n\
+# renewal_interval is how often mappings are renewed, in seconds. It must be shorter than lease_time.\n\
+# Renewals come sooner, at half the lease, if the router grants a shorter lease than asked.\n\
+# A failed renewal is retried after retry_initial_delay seconds, doubling up to retry_max_delay.\n\
+# The program gives up after retry_deadline seconds, or earlier if the lease would run out.\n\
+#\n\
+# internal_ip is the address the mappings point to. Leave it empty to use the system's\n\
+# preferred address, set it to \"auto\" to use the address facing the gateway, or give an address.\n\
+# interface picks the address of a network interface instead, e.g. \"eth0\".\n\
+#\n\
+# backend is the protocol used to talk to the router: \"upnp\", \"pcp\", \"natpmp\" or \"auto\"\n\
+# (default), which tries UPnP, then PCP, then NAT-PMP.\n\
+# gateway_ip is the router's address for PCP and NAT-PMP. Leave it empty to use the default route.\n\
+# PCP also accepts an IPv6 address, to open the router's IPv6 firewall for this device.\n\
+#\n\
+# gateway_url skips discovery and uses the router's root description directly,\n\
+# e.g. \"http://192.168.1.1:5000/rootDesc.xml\".\n\
+# Otherwise the router is searched for from bind_address (default any address) by sending to\n\
+# broadcast_address (default \"239.255.255.250:1900\"), for up to discovery_timeout seconds.\n\
+#\n\
+# Each [[mapping]] entry forwards one port on the router to this device.\n\
+# device_port is mandatory. Set it to a non-zero value to proceed.\n\
+# router_port is optional. If set to 0, it will be equal to the device port.\n\
+# device_ports = \"50000-50100\" forwards a range instead of device_port.\n\
+# router_port is then the start of the router range.\n\
+# protocol is optional. One of \"tcp\", \"udp\" or \"both\" (default).\n\
+# description is optional. It is shown in the router's port mapping table.\n\
+# It may use {hostname}, {protocol}, {device_port}, {router_port} and {pid}.\n\
+# host is optional. Set it to forward to another device on the network instead of this one.\n\
+# ipv6 = true also opens device_port on this device's global IPv6 address, through the\n\
+# router's IPv6 firewall. It needs a UPnP router that supports IPv6 pinholes. Pinholes last\n\
+# at most a day, so renewal_interval must be shorter than that.\n\n";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    /// Single-mapping format used before `[[mapping]]` existed. Migrated into
+    /// `mappings` on load.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    device_port: Option<u16>,
+    /// Written as `external_port` by early versions.
+    #[serde(
+        default,
+        alias = "external_port",
+        skip_serializing_if = "Option::is_none"
+    )]
+    router_port: Option<u16>,
+
+    #[serde(default = "default_lease_time")]
+    pub lease_time: u32,
+    #[serde(default = "default_renewal_interval")]
+    pub renewal_interval: u32,
+    #[serde(default = "default_retry_initial_delay")]
+    pub retry_initial_delay: u32,
+    #[serde(default = "default_retry_max_delay")]
+    pub retry_max_delay: u32,
+    #[serde(default = "default_retry_deadline")]
+    pub retry_deadline: u32,
+    #[serde(default)]
+    pub internal_ip: InternalIp,
+    #[serde(default)]
+    pub interface: String,
+    #[serde(default)]
+    pub backend: Backend,
+    #[serde(default)]
+    pub gateway_ip: String,
+    #[serde(default)]
+    pub gateway_url: String,
+    #[serde(default)]
+    pub bind_address: String,
+    #[serde(default)]
+    pub broadcast_address: String,
+    #[serde(default = "default_discovery_timeout")]
+    pub discovery_timeout: u32,
+
+    #[serde(default, rename = "mapping")]
+    pub mappings: Vec<Mapping>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Mapping {
+    #[serde(default)]
     pub device_port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_ports: Option<PortRange>,
+    #[serde(default)]
     pub router_port: u16,
+    #[serde(default)]
+    pub protocol: Protocol,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub ipv6: bool,
+    /// Another LAN device to forward to instead of this one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<Ipv4Addr>,
+}
+
+impl Mapping {
+    /// First device port of this entry.
+    fn device_start(&self) -> u16 {
+        self.device_ports
+            .map_or(self.device_port, |range| range.start)
+    }
+
+    /// First router port of this entry. A `router_port` of 0 mirrors the
+    /// device port.
+    fn router_start(&self) -> u16 {
+        if self.router_port == 0 {
+            self.device_start()
+        } else {
+            self.router_port
+        }
+    }
+
+    /// Last router port of this entry, or `None` if the router range would
+    /// run past 65535.
+    fn router_end(&self) -> Option<u16> {
+        let len = self.device_ports.map_or(0, |range| range.end - range.start);
+        self.router_start().checked_add(len)
+    }
+
+    /// Device/router port pairs forwarded by this entry, one per port.
+    pub fn ports(&self) -> Vec<(u16, u16)> {
+        match self.device_ports {
+            Some(range) => (range.start..=range.end)
+                .zip(self.router_start()..=self.router_end().unwrap_or(u16::MAX))
+                .collect(),
+            None => vec![(self.device_port, self.router_start())],
+        }
+    }
+}
+
+/// Inclusive port range written as `"start-end"`, or a single port.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let parse = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|e| format!("invalid port `{}` in range `{}`: {}", port, value, e))
+        };
+        let (start, end) = match value.split_once('-') {
+            Some((start, end)) => (parse(start)?, parse(end)?),
+            None => {
+                let port = parse(value)?;
+                (port, port)
+            }
+        };
+        if start == 0 || start > end {
+            return Err(format!("invalid port range `{}`", value));
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<PortRange> for String {
+    fn from(range: PortRange) -> Self {
+        range.to_string()
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    #[default]
+    Both,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        <Self as clap::ValueEnum>::from_str(value, true)
+    }
+}
+
+impl Protocol {
+    /// The IGD protocols a mapping with this setting creates.
+    pub fn igd_protocols(self) -> &'static [PortMappingProtocol] {
+        match self {
+            Protocol::Tcp => &[PortMappingProtocol::TCP],
+            Protocol::Udp => &[PortMappingProtocol::UDP],
+            Protocol::Both => &[PortMappingProtocol::TCP, PortMappingProtocol::UDP],
+        }
+    }
+}
+
+/// Protocol used to talk to the router.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Upnp,
+    Pcp,
+    #[value(name = "natpmp")]
+    NatPmp,
+    /// UPnP, or PCP and then NAT-PMP when no UPnP gateway is found.
+    #[default]
+    Auto,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        <Self as clap::ValueEnum>::from_str(value, true)
+    }
+}
+
+/// How the local address the mappings point to is chosen.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(try_from = "String", into = "String")]
+pub enum InternalIp {
+    /// The system's preferred address, written as an empty string.
+    #[default]
+    System,
+    /// The address used to reach the gateway, i.e. the one on its subnet.
+    Auto,
+    Fixed(Ipv4Addr),
+}
+
+impl FromStr for InternalIp {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim() {
+            "" => Ok(InternalIp::System),
+            "auto" => Ok(InternalIp::Auto),
+            ip => ip
+                .parse()
+                .map(InternalIp::Fixed)
+                .map_err(|e| format!("invalid IPv4 address `{}`: {}", ip, e)),
+        }
+    }
+}
+
+impl TryFrom<String> for InternalIp {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
 }
+
+impl From<InternalIp> for String {
+    fn from(internal_ip: InternalIp) -> Self {
+        match internal_ip {
+            InternalIp::System => String::new(),
+            InternalIp::Auto => "auto".to_string(),
+            InternalIp::Fixed(ip) => ip.to_string(),
+        }
+    }
+}
+
 impl Default for Config {
+    fn default() -> Self {
+        Self {
+            device_port: None,
+            router_port: None,
+            lease_time: DEFAULT_LEASE_TIME,
+            renewal_interval: DEFAULT_RENEWAL_INTERVAL,
+            retry_initial_delay: DEFAULT_RETRY_INITIAL_DELAY,
+            retry_max_delay: DEFAULT_RETRY_MAX_DELAY,
+            retry_deadline: DEFAULT_RETRY_DEADLINE,
+            internal_ip: InternalIp::System,
+            interface: String::new(),
+            backend: Backend::Auto,
+            gateway_ip: String::new(),
+            gateway_url: String::new(),
+            bind_address: String::new(),
+            broadcast_address: String::new(),
+            discovery_timeout: DEFAULT_DISCOVERY_TIMEOUT,
+            mappings: vec![Mapping::default()],
+        }
+    }
+}
+fn default_lease_time() -> u32 {
+    DEFAULT_LEASE_TIME
+}
+fn default_renewal_interval() -> u32 {
+    DEFAULT_RENEWAL_INTERVAL
+}
+fn default_retry_initial_delay() -> u32 {
+    DEFAULT_RETRY_INITIAL_DELAY
+}
+fn default_retry_max_delay() -> u32 {
+    DEFAULT_RETRY_MAX_DELAY
+}
+fn default_retry_deadline() -> u32 {
+    DEFAULT_RETRY_DEADLINE
+}
+fn default_discovery_timeout() -> u32 {
+    DEFAULT_DISCOVERY_TIMEOUT
+}
+
+impl Default for Mapping {
     fn default() -> Self {
         Self {
             device_port: 0,
+            device_ports: None,
             router_port: 0,
+            protocol: Protocol::Both,
+            description: String::new(),
+            ipv6: false,
+            host: None,
         }
     }
 }
 impl Config {
     /// Loads the configuration from the specified path.
-    /// If the file does not exist, creates one with default values.
-    pub fn load_or_create(path: &Path) -> io::Result<Self> {
+    ///
+    /// When `interactive` is set and the file is missing, it is written with
+    /// default values and the user is asked to edit it; the program then
+    /// exits. An existing file is never written, so an incomplete one is
+    /// returned for `validate` to report, comments intact.
+    pub fn load_or_create(path: &Path, interactive: bool) -> Result<Self, ConfigError> {
         if path.exists() {
-            let content = fs::read_to_string(path)?;
-            let mut config: Config = toml::from_str(&content).unwrap_or_default();
-
-            // Only write if the file is empty or invalidz
-            if !is_config_complete(&config) {
-                let toml_str = toml::to_string_pretty(&config).unwrap();
-                let content = format!(
-                    "# device_port is mandatory. Set it to a non-zero value to proceed.\n\
-                    # router_port is optional. If set to 0, it will be equal to the device port.\n\n\
-                    {}\n",
-                    toml_str
-                );
-                fs::write(path, content)?;
-                prompt();
+            return Config::load(path);
+        }
+        let config = Config::default();
+        if interactive {
+            write_config(path, &config)?;
+            prompt(path);
+        }
+        Ok(config)
+    }
+
+    /// Loads the configuration from the specified path without checking that
+    /// it is complete. The file is never written.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        Config::parse(&fs::read_to_string(path)?)
+    }
+
+    fn parse(content: &str) -> Result<Self, ConfigError> {
+        let mut config: Config =
+            toml::from_str(content).map_err(|e| ConfigError::from_toml(e, content))?;
+        config.migrate_single_mapping();
+        Ok(config)
+    }
+
+    /// Overrides config values with `UPNP_ENGAGE_*` environment variables.
+    ///
+    /// Values are layered as defaults, then the config file, then the
+    /// environment, then the command line. Setting `UPNP_ENGAGE_DEVICE_PORT`
+    /// or `UPNP_ENGAGE_DEVICE_PORTS` replaces the mappings from the file with
+    /// a single one. `UPNP_ENGAGE_PROTOCOL`, `UPNP_ENGAGE_DESCRIPTION` and
+    /// `UPNP_ENGAGE_IPV6` apply to every mapping.
+    pub fn apply_env(&mut self) -> Result<(), ValidationError> {
+        if let Some(lease_time) = env_value("lease_time")? {
+            self.lease_time = lease_time;
+        }
+        if let Some(renewal_interval) = env_value("renewal_interval")? {
+            self.renewal_interval = renewal_interval;
+        }
+        if let Some(retry_initial_delay) = env_value("retry_initial_delay")? {
+            self.retry_initial_delay = retry_initial_delay;
+        }
+        if let Some(retry_max_delay) = env_value("retry_max_delay")? {
+            self.retry_max_delay = retry_max_delay;
+        }
+        if let Some(retry_deadline) = env_value("retry_deadline")? {
+            self.retry_deadline = retry_deadline;
+        }
+        if let Some(internal_ip) = env_value("internal_ip")? {
+            self.internal_ip = internal_ip;
+        }
+        if let Some(interface) = env_value("interface")? {
+            self.interface = interface;
+        }
+        if let Some(backend) = env_value("backend")? {
+            self.backend = backend;
+        }
+        if let Some(gateway_ip) = env_value("gateway_ip")? {
+            self.gateway_ip = gateway_ip;
+        }
+        if let Some(gateway_url) = env_value("gateway_url")? {
+            self.gateway_url = gateway_url;
+        }
+        if let Some(bind_address) = env_value("bind_address")? {
+            self.bind_address = bind_address;
+        }
+        if let Some(broadcast_address) = env_value("broadcast_address")? {
+            self.broadcast_address = broadcast_address;
+        }
+        if let Some(discovery_timeout) = env_value("discovery_timeout")? {
+            self.discovery_timeout = discovery_timeout;
+        }
+
+        let device_port = env_value("device_port")?;
+        let device_ports = env_value("device_ports")?;
+        let router_port = env_value("router_port")?;
+        let host = env_value("host")?;
+        if device_port.is_some() || device_ports.is_some() {
+            self.mappings = vec![Mapping {
+                device_port: device_port.unwrap_or(0),
+                device_ports,
+                router_port: router_port.unwrap_or(0),
+                host,
+                ..Default::default()
+            }];
+        } else if router_port.is_some() || host.is_some() {
+            let field = if host.is_some() {
+                "host"
+            } else {
+                "router_port"
+            };
+            return Err(ValidationError::new(
+                field,
+                format!(
+                    "{}{} requires {}DEVICE_PORT or {}DEVICE_PORTS",
+                    ENV_PREFIX,
+                    field.to_uppercase(),
+                    ENV_PREFIX,
+                    ENV_PREFIX
+                ),
+            ));
+        }
+
+        let protocol = env_value("protocol")?;
+        let description = env_value::<String>("description")?;
+        let ipv6 = env_value("ipv6")?;
+        for mapping in &mut self.mappings {
+            if let Some(protocol) = protocol {
+                mapping.protocol = protocol;
             }
+            if let Some(ipv6) = ipv6 {
+                mapping.ipv6 = ipv6;
+            }
+            if let Some(description) = &description {
+                mapping.description = description.clone();
+            }
+        }
+        Ok(())
+    }
 
-            if config.router_port == 0 {
-                config.router_port = config.device_port;
+    /// Checks settings that are well-formed but unusable together.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.mappings.is_empty() {
+            return Err(ValidationError::new(
+                "mapping",
+                "at least one mapping is required",
+            ));
+        }
+        for (i, mapping) in self.mappings.iter().enumerate() {
+            let field = |name: &str| format!("mapping[{}].{}", i, name);
+            if mapping.device_port == 0 && mapping.device_ports.is_none() {
+                return Err(ValidationError::new(
+                    field("device_port"),
+                    "must be set to a non-zero port, or device_ports to a range",
+                ));
+            }
+            if mapping.device_port != 0 && mapping.device_ports.is_some() {
+                return Err(ValidationError::new(
+                    field("device_port"),
+                    "can't be combined with device_ports",
+                ));
+            }
+            if mapping.ipv6 && matches!(self.backend, Backend::Pcp | Backend::NatPmp) {
+                return Err(ValidationError::new(
+                    field("ipv6"),
+                    "IPv6 pinholes need the upnp backend",
+                ));
+            }
+            if mapping.router_end().is_none() {
+                return Err(ValidationError::new(
+                    field("router_port"),
+                    format!(
+                        "router range starting at {} runs past port 65535",
+                        mapping.router_start()
+                    ),
+                ));
+            }
+        }
+        if self.renewal_interval == 0 {
+            return Err(ValidationError::new(
+                "renewal_interval",
+                "must be greater than 0",
+            ));
+        }
+        if self.lease_time != 0 && self.renewal_interval >= self.lease_time {
+            return Err(ValidationError::new(
+                "renewal_interval",
+                format!(
+                    "must be shorter than lease_time ({}s), got {}s",
+                    self.lease_time, self.renewal_interval
+                ),
+            ));
+        }
+        // Pinholes can't be permanent, so they need renewing even when the
+        // IPv4 mappings don't.
+        let pinhole_lease = match self.lease_time {
+            0 => MAX_PINHOLE_LEASE,
+            lease_time => lease_time.min(MAX_PINHOLE_LEASE),
+        };
+        if self.mappings.iter().any(|m| m.ipv6) && self.renewal_interval >= pinhole_lease {
+            return Err(ValidationError::new(
+                "renewal_interval",
+                format!(
+                    "must be shorter than the longest IPv6 pinhole lease ({}s), got {}s",
+                    pinhole_lease, self.renewal_interval
+                ),
+            ));
+        }
+        if matches!(self.internal_ip, InternalIp::Fixed(_)) && !self.interface.is_empty() {
+            return Err(ValidationError::new(
+                "interface",
+                "can't be combined with a fixed internal_ip",
+            ));
+        }
+        if self.retry_initial_delay == 0 {
+            return Err(ValidationError::new(
+                "retry_initial_delay",
+                "must be greater than 0",
+            ));
+        }
+        if self.retry_max_delay < self.retry_initial_delay {
+            return Err(ValidationError::new(
+                "retry_max_delay",
+                format!(
+                    "must not be shorter than retry_initial_delay ({}s), got {}s",
+                    self.retry_initial_delay, self.retry_max_delay
+                ),
+            ));
+        }
+        if self.discovery_timeout == 0 {
+            return Err(ValidationError::new(
+                "discovery_timeout",
+                "must be greater than 0",
+            ));
+        }
+        if !self.gateway_url.is_empty() && !self.gateway_url.starts_with("http://") {
+            return Err(ValidationError::new(
+                "gateway_url",
+                format!("must be an http:// URL, got `{}`", self.gateway_url),
+            ));
+        }
+        if !self.gateway_url.is_empty() && matches!(self.backend, Backend::Pcp | Backend::NatPmp) {
+            return Err(ValidationError::new(
+                "gateway_url",
+                "is a UPnP setting and can't be used with the pcp or natpmp backend",
+            ));
+        }
+        self.search_options()?;
+        if let Some(IpAddr::V6(_)) = self.gateway_ip()? {
+            if self.backend == Backend::NatPmp {
+                return Err(ValidationError::new(
+                    "gateway_ip",
+                    "NAT-PMP only works over IPv4",
+                ));
             }
+            let host = self.mappings.iter().position(|m| m.host.is_some());
+            if let (Backend::Pcp, Some(i)) = (self.backend, host) {
+                return Err(ValidationError::new(
+                    format!("mapping[{}].host", i),
+                    "can't be used with an IPv6 gateway_ip; PCP over IPv6 only maps this device",
+                ));
+            }
+        }
+        Ok(())
+    }
 
-            Ok(config)
-        } else {
-            let config = Config::default();
-
-            let toml_str = toml::to_string_pretty(&config).unwrap();
-            let content = format!(
-                "# Device port must be correctly set to non-zero value.\n\
-                # If external port is set to 0, it will default to the device port.\n\n\
-                {}\n",
-                toml_str
-            );
+    /// The router to send PCP and NAT-PMP requests to, if set.
+    pub fn gateway_ip(&self) -> Result<Option<IpAddr>, ValidationError> {
+        if self.gateway_ip.is_empty() {
+            return Ok(None);
+        }
+        self.gateway_ip.trim().parse().map(Some).map_err(|_| {
+            ValidationError::new(
+                "gateway_ip",
+                format!("invalid IP address `{}`", self.gateway_ip),
+            )
+        })
+    }
 
-            fs::write(path, content)?;
-            prompt();
-            process::exit(0);
+    /// How to search for the gateway with SSDP. Empty addresses keep igd's
+    /// defaults.
+    pub fn search_options(&self) -> Result<SearchOptions, ValidationError> {
+        let mut options = SearchOptions {
+            timeout: Some(Duration::from_secs(self.discovery_timeout.into())),
+            ..Default::default()
+        };
+        if !self.bind_address.is_empty() {
+            options.bind_addr = parse_socket_addr(&self.bind_address, 0)
+                .map_err(|reason| ValidationError::new("bind_address", reason))?;
+        }
+        if !self.broadcast_address.is_empty() {
+            options.broadcast_address = parse_socket_addr(&self.broadcast_address, SSDP_PORT)
+                .map_err(|reason| ValidationError::new("broadcast_address", reason))?;
+        }
+        Ok(options)
+    }
+
+    /// Turns a top-level `device_port`/`router_port` pair into the first
+    /// `[[mapping]]` entry.
+    fn migrate_single_mapping(&mut self) {
+        let router_port = self.router_port.take().unwrap_or(0);
+        if let Some(device_port) = self.device_port.take() {
+            self.mappings.insert(
+                0,
+                Mapping {
+                    device_port,
+                    router_port,
+                    ..Default::default()
+                },
+            );
+        }
+        if self.mappings.is_empty() {
+            self.mappings.push(Mapping::default());
         }
     }
 
@@ -67,16 +670,190 @@ impl Config {
     // }
 }
 
-fn prompt() {
+/// Parses `ip:port`, or a bare IP address that gets `default_port`.
+fn parse_socket_addr(value: &str, default_port: u16) -> Result<SocketAddr, String> {
+    let value = value.trim();
+    value
+        .parse()
+        .or_else(|_| {
+            value
+                .parse::<IpAddr>()
+                .map(|ip| SocketAddr::new(ip, default_port))
+        })
+        .map_err(|_| format!("invalid address `{}`", value))
+}
+
+/// Whether the environment describes a mapping on its own, so the config file
+/// is optional.
+pub fn env_defines_mapping() -> bool {
+    env::var_os(format!("{}DEVICE_PORT", ENV_PREFIX)).is_some()
+        || env::var_os(format!("{}DEVICE_PORTS", ENV_PREFIX)).is_some()
+}
+
+/// Reads and parses the environment variable overriding `field`.
+fn env_value<T>(field: &str) -> Result<Option<T>, ValidationError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let name = format!("{}{}", ENV_PREFIX, field.to_uppercase());
+    match env::var(&name) {
+        Ok(value) => value.parse().map(Some).map_err(|e| {
+            ValidationError::new(
+                field,
+                format!("invalid value `{}` in {}: {}", value, name, e),
+            )
+        }),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(ValidationError::new(field, format!("{}: {}", name, e))),
+    }
+}
+
+/// Why a config could not be loaded or used.
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    /// The file is not valid TOML or doesn't match the expected settings.
+    /// Line and column are 1-based.
+    Parse {
+        line: Option<usize>,
+        column: Option<usize>,
+        /// The unknown or invalid setting, if the error names one.
+        field: Option<String>,
+        message: String,
+    },
+    Invalid(ValidationError),
+}
+
+impl ConfigError {
+    fn from_toml(error: toml::de::Error, content: &str) -> Self {
+        let (mut line, mut column) = match error.line_col() {
+            Some((line, column)) => (Some(line + 1), Some(column + 1)),
+            None => (None, None),
+        };
+
+        // toml appends the key and position to the message; keep them apart.
+        let mut message = error.to_string();
+        if let Some(at) = message.find(" at line ") {
+            message.truncate(at);
+        }
+        let mut field = None;
+        if let Some(at) = message.find(" for key `") {
+            field = Some(message[at + 10..].trim_end_matches('`').to_string());
+            message.truncate(at);
+        }
+        if let Some(rest) = message.strip_prefix("unknown field `") {
+            let unknown = rest.split('`').next().unwrap_or_default();
+
+            // toml points at the start of the table; point at the key instead.
+            if let Some((i, text)) = content
+                .lines()
+                .enumerate()
+                .skip(line.map_or(0, |l| l - 1))
+                .find(|(_, text)| {
+                    text.trim_start()
+                        .strip_prefix(unknown)
+                        .is_some_and(|rest| rest.trim_start().starts_with('='))
+                })
+            {
+                line = Some(i + 1);
+                column = Some(text.len() - text.trim_start().len() + 1);
+            }
+
+            field = Some(match field {
+                Some(table) => format!("{}.{}", table, unknown),
+                None => unknown.to_string(),
+            });
+        }
+
+        ConfigError::Parse {
+            line,
+            column,
+            field,
+            message,
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(error: io::Error) -> Self {
+        ConfigError::Io(error)
+    }
+}
+
+impl From<ValidationError> for ConfigError {
+    fn from(error: ValidationError) -> Self {
+        ConfigError::Invalid(error)
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => e.fmt(f),
+            ConfigError::Parse {
+                line: Some(line),
+                column: Some(column),
+                message,
+                ..
+            } => write!(f, "{} at line {} column {}", message, line, column),
+            ConfigError::Parse { message, .. } => message.fmt(f),
+            ConfigError::Invalid(e) => e.fmt(f),
+        }
+    }
+}
+
+/// A config setting that was parsed but can't be used.
+#[derive(Debug)]
+pub struct ValidationError {
+    /// Path of the offending setting, e.g. `mapping[0].device_port`.
+    pub field: String,
+    pub reason: String,
+}
+
+impl ValidationError {
+    fn new(field: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}` {}", self.field, self.reason)
+    }
+}
+
+fn write_config(path: &Path, config: &Config) -> io::Result<()> {
+    let toml_str = toml::to_string_pretty(config).unwrap();
+    fs::write(path, format!("{}{}\n", CONFIG_HEADER, toml_str))
+}
+
+fn prompt(path: &Path) {
     println!(
-        "Please set the device port in the config file at `config.toml`, in this directory.\n",
+        "Please set the device port in the config file at `{}`.\n",
+        path.display()
     );
     println!("Example:");
+    println!("[[mapping]]");
     println!("device_port = 8080");
-    println!("external_port = 0");
+    println!("router_port = 0");
+    println!("protocol = \"tcp\"");
+    println!("description = \"Web panel on {{hostname}} - {{protocol}}\"");
+
+    println!("\n[[mapping]]");
+    println!("device_ports = \"50000-50100\"");
+    println!("router_port = 60000");
+
+    println!("\n[[mapping]]");
+    println!("device_port = 3074");
+    println!("host = \"192.168.1.40\"");
 
     println!("\nDevice port must be correctly set to non-zero value.");
-    println!("If external port is set to 0, it will default to the device port.");
+    println!("If router port is set to 0, it will default to the device port.");
+    println!("Add one [[mapping]] block per port to forward.");
     println!("Please edit the file and rerun the program.");
     println!("Press Enter to close.");
     let mut buffer = String::new();
@@ -84,6 +861,88 @@ fn prompt() {
     process::exit(0);
 }
 
-pub fn is_config_complete(config: &Config) -> bool {
-    config.device_port != 0
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The line, column and field of the error parsing `content`.
+    fn parse_error(content: &str) -> (Option<usize>, Option<usize>, Option<String>) {
+        match Config::parse(content) {
+            Err(ConfigError::Parse {
+                line,
+                column,
+                field,
+                ..
+            }) => (line, column, field),
+            result => panic!("expected a parse error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn legacy_external_port_becomes_router_port() {
+        let config = Config::parse("device_port = 8080\nexternal_port = 9090\n").unwrap();
+        assert_eq!(config.mappings.len(), 1);
+        assert_eq!(config.mappings[0].device_port, 8080);
+        assert_eq!(config.mappings[0].router_port, 9090);
+    }
+
+    #[test]
+    fn unknown_top_level_key_is_located() {
+        let error = parse_error("lease_time = 60\nlease_tim = 5\n");
+        assert_eq!(error, (Some(2), Some(1), Some("lease_tim".to_string())));
+    }
+
+    #[test]
+    fn unknown_mapping_key_is_located() {
+        let error = parse_error("[[mapping]]\ndevice_port = 80\n  foo = 1\n");
+        assert_eq!(error, (Some(3), Some(3), Some("mapping.foo".to_string())));
+    }
+
+    #[test]
+    fn invalid_value_is_located() {
+        let error = parse_error("[[mapping]]\ndevice_port = \"x\"\n");
+        assert_eq!(
+            error,
+            (Some(2), Some(15), Some("mapping.device_port".to_string()))
+        );
+    }
+
+    #[test]
+    fn permanent_lease_still_renews_pinholes() {
+        let mut config = Config::parse(
+            "lease_time = 0\nrenewal_interval = 86400\n\n[[mapping]]\ndevice_port = 80\n",
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+
+        config.mappings[0].ipv6 = true;
+        let error = config.validate().unwrap_err();
+        assert_eq!(error.field, "renewal_interval");
+
+        config.renewal_interval = MAX_PINHOLE_LEASE - 1;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn incomplete_file_is_left_untouched() {
+        let path = env::temp_dir().join(format!("upnp-engage-{}.toml", process::id()));
+        let content = "# My game server\n[[mapping]]\ndevice_port = 80\ndevice_ports = \"80-81\"\n";
+        fs::write(&path, content).unwrap();
+
+        let config = Config::load_or_create(&path, true);
+        let written = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(written, content);
+        let error = config.unwrap().validate().unwrap_err();
+        assert_eq!(error.field, "mapping[0].device_port");
+    }
+
+    #[test]
+    fn message_leaves_out_position_and_key() {
+        let Err(ConfigError::Parse { message, .. }) = Config::parse("lease_time = \"x\"\n") else {
+            panic!("expected a parse error");
+        };
+        assert!(!message.contains(" at line "), "{}", message);
+        assert!(!message.contains("lease_time"), "{}", message);
+    }
 }
@@ -0,0 +1,177 @@
+// src/gateway.rs
+use igd::aio::tokio::Tokio;
+use igd::SearchError;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{self, TcpStream};
+use url::Url;
+use xmltree::Element;
+
+pub type IgdGateway = igd::aio::Gateway<Tokio>;
+
+/// Services that can add port mappings, in the order igd looks for them.
+const WAN_SERVICES: &[&str] = &[
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+];
+
+/// Connects to the gateway whose root description is at `url`, without SSDP.
+pub async fn from_url(url: &str) -> Result<IgdGateway, SearchError> {
+    let root_url = Url::parse(url).map_err(|_| SearchError::InvalidResponse)?;
+    let addr = resolve(&root_url).await?;
+
+    let root = Element::parse(&get(&root_url).await?[..])?;
+    let (scpd_url, control_url) =
+        find_service(&root, WAN_SERVICES).ok_or(SearchError::InvalidResponse)?;
+    let scpd_url = join(&root_url, &scpd_url)?;
+    let control_url = join(&root_url, &control_url)?;
+
+    let scpd = Element::parse(&get(&scpd_url).await?[..])?;
+    let control_schema = parse_actions(&scpd).ok_or(SearchError::InvalidResponse)?;
+
+    Ok(IgdGateway {
+        addr,
+        root_url: root_url.path().to_string(),
+        control_url: path_of(&control_url),
+        control_schema_url: path_of(&scpd_url),
+        control_schema,
+        provider: Tokio,
+    })
+}
+
+/// The address of the host serving `url`.
+pub async fn resolve(url: &Url) -> Result<SocketAddr, SearchError> {
+    let host = url.host_str().ok_or(SearchError::InvalidResponse)?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = url.port_or_known_default().unwrap_or(80);
+    net::lookup_host((host, port))
+        .await?
+        .next()
+        .ok_or(SearchError::InvalidResponse)
+}
+
+/// Fetches `url` with a plain HTTP GET.
+pub async fn get(url: &Url) -> Result<Vec<u8>, SearchError> {
+    match http(url, "GET", &[], "").await? {
+        (200, body) => Ok(body),
+        _ => Err(SearchError::InvalidResponse),
+    }
+}
+
+/// Sends a request with HTTP/1.0, which keeps routers from sending a chunked
+/// body. Returns the status code and body.
+pub async fn http(
+    url: &Url,
+    method: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> io::Result<(u16, Vec<u8>)> {
+    let addr = resolve(url)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("bad URL {}", url)))?;
+    let mut stream = TcpStream::connect(addr).await?;
+    let mut request = format!(
+        "{} {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method,
+        path_of(url),
+        url.host_str().unwrap_or_default(),
+        body.len()
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP response");
+    let body_at = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(invalid)?;
+    let status = String::from_utf8_lossy(&response[..body_at])
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(invalid)?;
+    Ok((status, response.split_off(body_at + 4)))
+}
+
+/// Finds the first service of one of `service_types` on the device described
+/// by `root` or any of its embedded devices. Returns its SCPD and control
+/// URLs as written in the description.
+pub fn find_service(root: &Element, service_types: &[&str]) -> Option<(String, String)> {
+    let device = root.get_child("device")?;
+    find_in_device(device, service_types)
+}
+
+fn find_in_device(device: &Element, service_types: &[&str]) -> Option<(String, String)> {
+    let services = device
+        .get_child("serviceList")
+        .into_iter()
+        .flat_map(|list| child_elements(list, "service"));
+    for service in services {
+        let service_type = text_of(service, "serviceType").unwrap_or_default();
+        if service_types.contains(&service_type.as_str()) {
+            return Some((
+                text_of(service, "SCPDURL")?,
+                text_of(service, "controlURL")?,
+            ));
+        }
+    }
+    device
+        .get_child("deviceList")
+        .into_iter()
+        .flat_map(|list| child_elements(list, "device"))
+        .find_map(|device| find_in_device(device, service_types))
+}
+
+/// Maps each action of a service description to its input arguments.
+fn parse_actions(scpd: &Element) -> Option<HashMap<String, Vec<String>>> {
+    let actions = child_elements(scpd.get_child("actionList")?, "action")
+        .filter_map(|action| {
+            let arguments = action
+                .get_child("argumentList")
+                .into_iter()
+                .flat_map(|list| child_elements(list, "argument"))
+                .filter(|argument| text_of(argument, "direction").as_deref() == Some("in"))
+                .filter_map(|argument| text_of(argument, "name"))
+                .collect();
+            Some((text_of(action, "name")?, arguments))
+        })
+        .collect();
+    Some(actions)
+}
+
+fn child_elements<'a>(
+    element: &'a Element,
+    name: &'a str,
+) -> impl Iterator<Item = &'a Element> + 'a {
+    element
+        .children
+        .iter()
+        .filter_map(|child| child.as_element())
+        .filter(move |child| child.name == name)
+}
+
+pub fn text_of(element: &Element, child: &str) -> Option<String> {
+    Some(element.get_child(child)?.get_text()?.trim().to_string())
+}
+
+/// Resolves a URL from a description against the description's own URL.
+pub fn join(base: &Url, url: &str) -> Result<Url, SearchError> {
+    base.join(url).map_err(|_| SearchError::InvalidResponse)
+}
+
+/// The path and query of `url`, as igd sends them to the gateway's address.
+pub fn path_of(url: &Url) -> String {
+    match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    }
+}
@@ -1,187 +1,883 @@
+mod cli;
 mod config;
-mod deferred_task;
+mod gateway;
+mod mapper;
+mod natpmp;
+mod pcp;
+mod pinhole;
 mod platform;
+#[cfg(test)]
+mod test_support;
+mod wire;
 
-use config::Config;
-use deferred_task::DeferredTask;
-use igd::search_gateway;
-use igd::PortMappingProtocol;
-use local_ip_address;
-use platform::windows::register_windows_console_ctrl_handler;
+use clap::Parser;
+use cli::Cli;
+use config::{Backend, Config, ConfigError, InternalIp, Mapping};
+use igd::aio::tokio::search_gateway;
+use igd::{PortMappingProtocol, RequestError, SearchError};
+use mapper::{PortMapper, Upnp};
+use natpmp::NatPmp;
+use pcp::Pcp;
+use pinhole::{Firewall, MAX_PINHOLE_LEASE};
+use platform::register_termination_handler;
+use rand::Rng;
 use std::env;
 use std::io;
-use std::net::Ipv4Addr;
-use std::net::SocketAddrV4;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::{SocketAddr, SocketAddrV4, UdpSocket};
+use std::path::Path;
 use std::process;
-use std::str::FromStr;
-use std::sync::Arc;
-use std::sync::Mutex;
 use std::sync::OnceLock;
-use tokio::time::{self, Duration};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{watch, Notify};
+use tokio::task;
+use tokio::time::{self, Duration, Instant};
 
-const LEASE_TIME: u32 = 3600;
-const LEASE_RENEWAL_INTERVAL: u32 = 3000;
-const CONNECTION_NAME: &str = "Rust UPnP Port Forwarder";
+/// Exit code for a config that can't be used, as `EX_CONFIG` in sysexits.h.
+const EXIT_INVALID_CONFIG: i32 = 78;
 
-static TASK_OPEN_AND_MAINTAIN_CONNECTION: OnceLock<Arc<Mutex<DeferredTask>>> = OnceLock::new();
+/// How long cleanup may take on shutdown. Windows terminates the process about
+/// five seconds after its console window is closed.
+const CLEANUP_TIMEOUT: Duration = Duration::from_secs(4);
+
+/// How long the gateway may take to answer an attempt to add or renew a
+/// mapping. The SOAP requests have no timeout of their own.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+const DEFAULT_DESCRIPTION: &str = "Rust UPnP Port Forwarder - {protocol}";
+
+static HOSTNAME: OnceLock<String> = OnceLock::new();
 
 fn get_config_path() -> io::Result<std::path::PathBuf> {
     let current_dir = env::current_dir()?;
     Ok(current_dir.join("config.toml"))
 }
 
-// async fn cleanup_ports(gateway: &igd::aio::Gateway, router_port: u16) {
-//     // Remove TCP port mapping
-//     let _ = File::create("empty_clean.txt");
-//     match gateway
-//         .remove_port(PortMappingProtocol::TCP, router_port)
-//         .await
-//     {
-//         Ok(_) => println!("TCP port mapping removed successfully."),
-//         Err(e) => eprintln!("Failed to remove TCP port mapping: {}", e),
-//     }
-
-//     // Remove UDP port mapping
-//     match gateway
-//         .remove_port(PortMappingProtocol::UDP, router_port)
-//         .await
-//     {
-//         Ok(_) => println!("UDP port mapping removed successfully."),
-//         Err(e) => eprintln!("Failed to remove UDP port mapping: {}", e),
-//     }
-// }
-
-async fn open_and_keep_active(gateway: igd::Gateway, device_port: u16, external_port: u16) {
-    let local_ip = local_ip_address::local_ip()
-        .unwrap_or_else(|e| {
-            eprintln!("Failed to get local IP: {}", e);
-            process::exit(1);
-        })
-        .to_string();
-    let local_ip = Ipv4Addr::from_str(&local_ip).unwrap();
-    let external_ip = gateway.get_external_ip().unwrap_or_else(|e| {
-        eprintln!("Failed to get external IP: {}", e);
-        process::exit(1);
-    });
-    let renewal_interval = Duration::from_secs(LEASE_RENEWAL_INTERVAL.into());
-    let mut first_run = true;
+fn exit_invalid_config(config_path: &Path, error: ConfigError) -> ! {
+    eprintln!("Invalid config:");
+    eprintln!("  file:   {}", config_path.display());
+    match error {
+        ConfigError::Io(e) => eprintln!("  reason: {}", e),
+        ConfigError::Parse {
+            line,
+            column,
+            field,
+            message,
+        } => {
+            if let (Some(line), Some(column)) = (line, column) {
+                eprintln!("  line:   {}", line);
+                eprintln!("  column: {}", column);
+            }
+            if let Some(field) = field {
+                eprintln!("  field:  {}", field);
+            }
+            eprintln!("  reason: {}", message);
+        }
+        ConfigError::Invalid(e) => {
+            eprintln!("  field:  {}", e.field);
+            eprintln!("  reason: {}", e.reason);
+        }
+    }
+    process::exit(EXIT_INVALID_CONFIG);
+}
 
-    loop {
-        // Add/Renew TCP Port Mapping
-        match gateway.add_port(
-            igd::PortMappingProtocol::TCP,
-            external_port,
-            SocketAddrV4::new(local_ip, device_port),
-            // external IP works, router recognizes itself
-            LEASE_TIME,
-            &format!("{} - TCP", CONNECTION_NAME),
-        ) {
-            Ok(_) => {
-                if first_run {
-                    println!("✓ TCP port active.");
-                } else {
-                    println!("✓ TCP port renewed.");
+/// Builds the description shown in the router's mapping table from the
+/// mapping's template, filling in `{hostname}`, `{protocol}`,
+/// `{device_port}`, `{router_port}` and `{pid}`.
+fn expand_description(
+    mapping: &Mapping,
+    protocol: PortMappingProtocol,
+    (device_port, router_port): (u16, u16),
+) -> String {
+    let template = if mapping.description.is_empty() {
+        DEFAULT_DESCRIPTION
+    } else {
+        &mapping.description
+    };
+    let hostname =
+        HOSTNAME.get_or_init(|| gethostname::gethostname().to_string_lossy().into_owned());
+
+    template
+        .replace("{hostname}", hostname)
+        .replace("{protocol}", &protocol.to_string())
+        .replace("{device_port}", &device_port.to_string())
+        .replace("{router_port}", &router_port.to_string())
+        .replace("{pid}", &process::id().to_string())
+}
+
+/// Adds or renews the mapping of one port for one protocol. Returns the lease
+/// the gateway granted, 0 if permanent.
+async fn add_or_renew_port(
+    gateway: &dyn PortMapper,
+    protocol: PortMappingProtocol,
+    local_ip: Ipv4Addr,
+    mapping: &Mapping,
+    (device_port, router_port): (u16, u16),
+    lease_time: u32,
+    first_run: bool,
+) -> Result<u32, igd::AddPortError> {
+    let description = expand_description(mapping, protocol, (device_port, router_port));
+    let target_ip = mapping.host.unwrap_or(local_ip);
+    let local_addr = SocketAddr::V4(SocketAddrV4::new(target_ip, device_port));
+    // external IP works, router recognizes itself
+    let result = if first_run {
+        gateway
+            .add(protocol, router_port, local_addr, lease_time, &description)
+            .await
+    } else {
+        gateway
+            .renew(protocol, router_port, local_addr, lease_time, &description)
+            .await
+    };
+    match &result {
+        Ok(_) if first_run => println!("✓ {} port {} active.", protocol, router_port),
+        Ok(_) => println!("✓ {} port {} renewed.", protocol, router_port),
+        // UPnP errors 606 and 718: many gateways only accept mappings that
+        // point at the device asking for them.
+        Err(igd::AddPortError::ActionNotAuthorized | igd::AddPortError::PortInUse)
+            if mapping.host.is_some() =>
+        {
+            eprintln!(
+                "Warning: the gateway refused to forward {} port {} to {}. \
+                It may not allow mappings on behalf of other devices.",
+                protocol, router_port, target_ip
+            );
+        }
+        Err(igd::AddPortError::PortInUse) => report_conflict(gateway, protocol, router_port).await,
+        Err(_) => {}
+    }
+    result
+}
+
+/// Tells which device already has `router_port`, if the gateway can list its
+/// mappings.
+async fn report_conflict(
+    gateway: &dyn PortMapper,
+    protocol: PortMappingProtocol,
+    router_port: u16,
+) {
+    let Ok(entries) = gateway.list().await else {
+        return;
+    };
+    if let Some(entry) = entries
+        .iter()
+        .find(|entry| entry.protocol == protocol && entry.external_port == router_port)
+    {
+        eprintln!(
+            "{} port {} is already forwarded to {}:{} ({}).",
+            protocol,
+            router_port,
+            entry.internal_client,
+            entry.internal_port,
+            entry.port_mapping_description
+        );
+    }
+}
+
+/// Opens or renews the IPv6 pinhole for `device_port` on `local_ipv6`, this
+/// device's global address. Without a firewall service or an IPv6 address, or
+/// when the gateway refuses the pinhole, only a warning is printed, and the
+/// IPv4 mapping stays.
+async fn add_or_renew_pinhole(
+    gateway: &dyn PortMapper,
+    protocol: PortMappingProtocol,
+    local_ipv6: &Result<Ipv6Addr, String>,
+    config: &Config,
+    device_port: u16,
+    first_run: bool,
+) -> Result<(), igd::AddPortError> {
+    let Some(firewall) = gateway.firewall() else {
+        if first_run {
+            eprintln!(
+                "Warning: the gateway can't open IPv6 pinholes. {} port {} is IPv4 only.",
+                protocol, device_port
+            );
+        }
+        return Ok(());
+    };
+    let local_ipv6 = match local_ipv6 {
+        Ok(ip) => *ip,
+        Err(e) => {
+            eprintln!(
+                "Warning: no IPv6 pinhole for {} port {}: {}",
+                protocol, device_port, e
+            );
+            return Ok(());
+        }
+    };
+    // Pinholes can't be permanent.
+    let lease_time = match config.lease_time {
+        0 => MAX_PINHOLE_LEASE,
+        lease_time => lease_time.min(MAX_PINHOLE_LEASE),
+    };
+
+    match firewall
+        .add_or_update(protocol, local_ipv6, device_port, lease_time)
+        .await
+    {
+        Ok(()) => {}
+        Err(e @ (igd::AddPortError::ActionNotAuthorized | igd::AddPortError::PortInUse)) => {
+            eprintln!(
+                "Warning: no IPv6 pinhole for {} port {}: {}",
+                protocol, device_port, e
+            );
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    }
+    if first_run {
+        println!(
+            "✓ {} pinhole [{}]:{} open.",
+            protocol, local_ipv6, device_port
+        );
+    } else {
+        println!(
+            "✓ {} pinhole [{}]:{} renewed.",
+            protocol, local_ipv6, device_port
+        );
+    }
+    Ok(())
+}
+
+/// Adds or renews every configured port mapping. A failed attempt is retried
+/// with exponential backoff and jitter until `retry_until`; without a
+/// deadline the first failure is returned. An attempt the gateway doesn't
+/// answer within `REQUEST_TIMEOUT` fails like one that can't reach it.
+///
+/// When a retried attempt can't reach the gateway at all, the gateway is
+/// discovered again and every mapping is re-created on it.
+async fn add_or_renew_all(
+    gateway: &watch::Sender<Arc<dyn PortMapper>>,
+    created: &Mutex<Vec<CreatedMapping>>,
+    local_ip: Ipv4Addr,
+    config: &Config,
+    first_run: bool,
+    retry_until: Option<Instant>,
+) -> Result<(), igd::AddPortError> {
+    let action = if first_run { "add" } else { "renew" };
+    let local_ipv6 = current_local_ipv6(config);
+    let mut recreate = true;
+
+    while recreate {
+        recreate = false;
+        for (mapping, ports, protocol) in each_port(&config.mappings) {
+            let router_port = ports.1;
+            let mut delay = Duration::from_secs(config.retry_initial_delay.into());
+            let mut attempt = 1;
+            loop {
+                let current = gateway.borrow().clone();
+                let attempt_future = async {
+                    let lease = add_or_renew_port(
+                        current.as_ref(),
+                        protocol,
+                        local_ip,
+                        mapping,
+                        ports,
+                        config.lease_time,
+                        first_run,
+                    )
+                    .await?;
+                    record(
+                        created,
+                        CreatedMapping {
+                            protocol,
+                            router_port,
+                            local_addr: SocketAddr::new(
+                                mapping.host.unwrap_or(local_ip).into(),
+                                ports.0,
+                            ),
+                            lease,
+                            ipv6: mapping.ipv6,
+                            other_device: mapping.host.is_some(),
+                        },
+                    );
+                    if mapping.ipv6 {
+                        add_or_renew_pinhole(
+                            current.as_ref(),
+                            protocol,
+                            &local_ipv6,
+                            config,
+                            ports.0,
+                            first_run,
+                        )
+                        .await?;
+                    }
+                    Ok(())
+                };
+                let mut deadline = Instant::now() + REQUEST_TIMEOUT;
+                if let Some(retry_until) = retry_until {
+                    deadline = deadline.min(retry_until);
                 }
-            }
-            Err(e) => {
+                // A timeout counts as unreachable, so the gateway is looked for again.
+                let result = time::timeout_at(deadline, attempt_future)
+                    .await
+                    .unwrap_or_else(|_| Err(igd::AddPortError::RequestError(timed_out())));
+                let e = match result {
+                    Ok(()) => {
+                        if attempt > 1 {
+                            println!(
+                                "{} port {} recovered on attempt {}.",
+                                protocol, router_port, attempt
+                            );
+                        }
+                        break;
+                    }
+                    Err(e) => e,
+                };
+
+                let wait = with_jitter(delay);
+                let retry_until = match retry_until {
+                    Some(retry_until) if Instant::now() + wait < retry_until => retry_until,
+                    _ => {
+                        eprintln!(
+                            "Failed to {} {} port mapping {} (attempt {}): {}",
+                            action, protocol, router_port, attempt, e
+                        );
+                        return Err(e);
+                    }
+                };
                 eprintln!(
-                    "Failed to {} TCP port mapping: {}",
-                    if first_run { "add" } else { "renew" },
-                    e
+                    "Failed to {} {} port mapping {} (attempt {}): {}. Retrying in {:.1}s, \
+                    giving up in {}s.",
+                    action,
+                    protocol,
+                    router_port,
+                    attempt,
+                    e,
+                    wait.as_secs_f32(),
+                    (retry_until - Instant::now()).as_secs()
                 );
-                process::exit(1);
+                if is_connection_error(&e) && rediscover_gateway(gateway, config).await {
+                    // Mappings renewed earlier in this pass went to the old gateway.
+                    recreate = true;
+                }
+                time::sleep(wait).await;
+                delay = (delay * 2).min(Duration::from_secs(config.retry_max_delay.into()));
+                attempt += 1;
             }
         }
+        if recreate {
+            println!("Re-creating all mappings on the rediscovered gateway.");
+        }
+    }
+    Ok(())
+}
 
-        // Add/Renew UDP Port Mapping
-        match gateway.add_port(
-            igd::PortMappingProtocol::UDP,
-            external_port,
-            SocketAddrV4::new(local_ip, device_port),
-            LEASE_TIME,
-            &format!("{} - UDP", CONNECTION_NAME),
-        ) {
-            Ok(_) => {
-                if first_run {
-                    println!("✓ UDP port active.");
-                } else {
-                    println!("✓ UDP port renewed.");
-                }
+/// The error of a request the gateway didn't answer in time.
+fn timed_out() -> RequestError {
+    RequestError::IoError(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "the gateway didn't answer",
+    ))
+}
+
+/// Whether a request never got an answer from the gateway, e.g. because it
+/// rebooted or its control URL changed.
+fn is_connection_error(e: &igd::AddPortError) -> bool {
+    matches!(
+        e,
+        igd::AddPortError::RequestError(
+            RequestError::HyperClientError(_)
+                | RequestError::HyperError(_)
+                | RequestError::IoError(_)
+        )
+    )
+}
+
+/// Finds the gateway with the configured backend. In auto mode PCP and then
+/// NAT-PMP are tried when no UPnP gateway answers.
+async fn discover_gateway(config: &Config) -> Result<Arc<dyn PortMapper>, String> {
+    if matches!(config.backend, Backend::Upnp | Backend::Auto) {
+        match discover_upnp_gateway(config).await {
+            Ok(gateway) => {
+                let firewall = find_firewall(config, &gateway).await;
+                return Ok(Arc::new(Upnp::new(gateway, firewall)));
             }
-            Err(e) => {
-                eprintln!(
-                    "Failed to {} UDP port mapping: {}",
-                    if first_run { "add" } else { "renew" },
-                    e
-                );
-                process::exit(1);
+            Err(e) if config.backend == Backend::Upnp => return Err(e.to_string()),
+            Err(e) => println!("No UPnP gateway found: {}. Trying PCP.", e),
+        }
+    }
+
+    // validate() has checked gateway_ip.
+    let ip = match config.gateway_ip().ok().flatten() {
+        Some(ip) => ip,
+        None => platform::default_gateway()
+            .await
+            .ok_or("no default route to find the gateway on. Set gateway_ip.")?
+            .into(),
+    };
+
+    if matches!(config.backend, Backend::Pcp | Backend::Auto) {
+        match Pcp::connect(SocketAddr::new(ip, pcp::PCP_PORT)).await {
+            Ok(pcp) => return Ok(Arc::new(pcp)),
+            Err(e) if config.backend == Backend::Pcp || ip.is_ipv6() => {
+                return Err(format!("no PCP server at {}: {}", ip, e))
             }
+            Err(e) => println!("No PCP server at {}: {}. Trying NAT-PMP.", ip, e),
         }
+    }
 
-        if first_run {
-            println!("");
-            println!("Port forwarding is active.");
-            println!("\nLocal IP:");
-            println!("{}:{}", local_ip, device_port);
-            println!("\nExternal IP:");
-            println!("{}:{}", external_ip, external_port);
-            println!("");
-            println!("Press Ctrl+C to terminate.");
+    let IpAddr::V4(ip) = ip else {
+        return Err(format!("NAT-PMP needs an IPv4 gateway, got {}", ip));
+    };
+    NatPmp::connect(ip)
+        .await
+        .map(|natpmp| Arc::new(natpmp) as Arc<dyn PortMapper>)
+        .map_err(|e| format!("no NAT-PMP gateway at {}: {}", ip, e))
+}
+
+/// Looks up the IPv6 firewall control of `gateway` if a mapping asks for
+/// pinholes, giving up after `discovery_timeout`.
+async fn find_firewall(config: &Config, gateway: &gateway::IgdGateway) -> Option<Firewall> {
+    if !config.mappings.iter().any(|mapping| mapping.ipv6) {
+        return None;
+    }
+    let timeout = Duration::from_secs(config.discovery_timeout.into());
+    let result = time::timeout(timeout, Firewall::find(gateway))
+        .await
+        .unwrap_or(Err(SearchError::NoResponseWithinTimeout));
+    result.unwrap_or_else(|e| {
+        eprintln!("Failed to look up the IPv6 firewall of the gateway: {}", e);
+        None
+    })
+}
+
+/// Finds the UPnP gateway at `gateway_url`, or searches for it with SSDP.
+async fn discover_upnp_gateway(config: &Config) -> Result<gateway::IgdGateway, SearchError> {
+    if config.gateway_url.is_empty() {
+        // validate() has checked the addresses.
+        let options = config.search_options().unwrap_or_default();
+        return search_gateway(options).await;
+    }
+    let timeout = Duration::from_secs(config.discovery_timeout.into());
+    time::timeout(timeout, gateway::from_url(&config.gateway_url))
+        .await
+        .unwrap_or(Err(SearchError::NoResponseWithinTimeout))
+}
+
+/// Runs discovery again, for at most `discovery_timeout`, and swaps in the
+/// gateway it finds if it moved. Returns whether it was swapped in.
+async fn rediscover_gateway(gateway: &watch::Sender<Arc<dyn PortMapper>>, config: &Config) -> bool {
+    println!("Gateway unreachable. Searching for it again...");
+    let timeout = Duration::from_secs(config.discovery_timeout.into());
+    let result = time::timeout(timeout, discover_gateway(config))
+        .await
+        .unwrap_or_else(|_| Err("no gateway answered in time".to_string()));
+    match result {
+        Ok(found) => {
+            // Keep the gateway if it didn't move, along with what it tracks
+            // about the mappings, e.g. PCP nonces.
+            // The description names the protocol and control URL.
+            if gateway.borrow().to_string() == found.to_string() {
+                println!("Gateway found again at {}.", found);
+                return false;
+            }
+            println!("Gateway moved from {} to {}.", *gateway.borrow(), found);
+            gateway.send_replace(found);
+            true
+        }
+        Err(e) => {
+            eprintln!("Failed to rediscover gateway: {}", e);
+            false
         }
+    }
+}
+
+/// The local IPv4 address the mappings point to, chosen by the
+/// `internal_ip` and `interface` settings.
+fn current_local_ip(config: &Config, gateway: &dyn PortMapper) -> Result<Ipv4Addr, String> {
+    if let InternalIp::Fixed(ip) = config.internal_ip {
+        return Ok(ip);
+    }
 
-        first_run = false;
-        time::sleep(renewal_interval).await;
+    if !config.interface.is_empty() {
+        let interfaces = local_ip_address::list_afinet_netifas().map_err(|e| e.to_string())?;
+        return interfaces
+            .into_iter()
+            .find_map(|(name, ip)| match ip {
+                IpAddr::V4(ip) if name == config.interface => Some(ip),
+                _ => None,
+            })
+            .ok_or_else(|| format!("no IPv4 address on interface `{}`", config.interface));
+    }
+
+    let ip = match config.internal_ip {
+        // Let the routing table pick the address that reaches the gateway.
+        InternalIp::Auto => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .and_then(|socket| {
+                socket.connect(gateway.addr())?;
+                socket.local_addr()
+            })
+            .map(|addr| addr.ip())
+            .map_err(|e| format!("no route to gateway {}: {}", gateway.addr(), e))?,
+        _ => local_ip_address::local_ip().map_err(|e| e.to_string())?,
+    };
+    match ip {
+        IpAddr::V4(ip) => Ok(ip),
+        IpAddr::V6(ip) => Err(format!("{} is not an IPv4 address", ip)),
     }
 }
 
-fn cleanup_ports(gateway: igd::Gateway, router_port: u16) {
-    // Create or truncate the "empty_clean.txt" file
+/// This device's global IPv6 address, on `interface` if set. Otherwise the
+/// routing table picks the address that reaches the internet.
+fn current_local_ipv6(config: &Config) -> Result<Ipv6Addr, String> {
+    let is_global = |ip: &Ipv6Addr| ip.segments()[0] & 0xe000 == 0x2000;
 
-    // Remove TCP port mapping
-    match gateway.remove_port(PortMappingProtocol::TCP, router_port) {
-        Ok(_) => println!("TCP port mapping removed successfully."),
-        Err(e) => eprintln!("Failed to remove TCP port mapping: {}", e),
+    if !config.interface.is_empty() {
+        let interfaces = local_ip_address::list_afinet_netifas().map_err(|e| e.to_string())?;
+        return interfaces
+            .into_iter()
+            .find_map(|(name, ip)| match ip {
+                IpAddr::V6(ip) if name == config.interface && is_global(&ip) => Some(ip),
+                _ => None,
+            })
+            .ok_or_else(|| format!("no global IPv6 address on interface `{}`", config.interface));
     }
 
-    // Remove UDP port mapping
-    match gateway.remove_port(PortMappingProtocol::UDP, router_port) {
-        Ok(_) => println!("UDP port mapping removed successfully."),
-        Err(e) => eprintln!("Failed to remove UDP port mapping: {}", e),
+    // Connecting a UDP socket sends nothing; it only picks the source address.
+    let internet = SocketAddr::from((Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 9));
+    let ip = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))
+        .and_then(|socket| {
+            socket.connect(internet)?;
+            socket.local_addr()
+        })
+        .map(|addr| addr.ip())
+        .map_err(|e| format!("no IPv6 route: {}", e))?;
+    match ip {
+        IpAddr::V6(ip) if is_global(&ip) => Ok(ip),
+        ip => Err(format!("{} is not a global IPv6 address", ip)),
     }
 }
 
-fn shutdown_program(gateway: igd::Gateway, external_port: u16) {
-    if TASK_OPEN_AND_MAINTAIN_CONNECTION.get().is_none() {
-        return;
+/// Every port of every mapping, once per protocol.
+fn each_port(
+    mappings: &[Mapping],
+) -> impl Iterator<Item = (&Mapping, (u16, u16), PortMappingProtocol)> {
+    mappings.iter().flat_map(|mapping| {
+        mapping.ports().into_iter().flat_map(move |ports| {
+            mapping
+                .protocol
+                .igd_protocols()
+                .iter()
+                .map(move |&protocol| (mapping, ports, protocol))
+        })
+    })
+}
+
+/// A mapping this run created on the gateway. Only these are removed again,
+/// so a port another device holds is left alone.
+#[derive(Debug, Clone, Copy)]
+struct CreatedMapping {
+    protocol: PortMappingProtocol,
+    router_port: u16,
+    /// The device and port the mapping forwards to.
+    local_addr: SocketAddr,
+    /// The lease granted at the last add or renewal, 0 if permanent.
+    lease: u32,
+    /// Whether a pinhole was opened for it, too.
+    ipv6: bool,
+    /// Whether it forwards to another device, and stays when this device's
+    /// address changes.
+    other_device: bool,
+}
+
+/// Remembers `mapping`, replacing an earlier one of the same router port.
+fn record(created: &Mutex<Vec<CreatedMapping>>, mapping: CreatedMapping) {
+    let mut created = created.lock().unwrap();
+    created.retain(|m| (m.protocol, m.router_port) != (mapping.protocol, mapping.router_port));
+    created.push(mapping);
+}
+
+/// The shortest lease granted for the created mappings, or `None` if they are
+/// all permanent.
+fn shortest_lease(created: &Mutex<Vec<CreatedMapping>>) -> Option<u32> {
+    let created = created.lock().unwrap();
+    created
+        .iter()
+        .map(|m| m.lease)
+        .filter(|&lease| lease != 0)
+        .min()
+}
+
+/// How long to wait between renewals: `renewal_interval`, or half of `lease`
+/// if the gateway granted less than asked, as RFC 6886 and RFC 6887 advise.
+fn renewal_interval(config: &Config, lease: Option<u32>) -> Duration {
+    let interval = Duration::from_secs(config.renewal_interval.into());
+    match lease {
+        Some(lease) => interval.min(Duration::from_secs((lease / 2).max(1).into())),
+        None => interval,
     }
+}
 
-    let task = TASK_OPEN_AND_MAINTAIN_CONNECTION
-        .get()
-        .unwrap()
-        .lock()
-        .unwrap();
-    task.abort_and_wait();
-    cleanup_ports(gateway, external_port);
+/// Picks a delay between half and all of `delay`, so clients that failed
+/// together don't retry together.
+fn with_jitter(delay: Duration) -> Duration {
+    delay.mul_f64(rand::rng().random_range(0.5..=1.0))
+}
+
+/// When renewal retries must stop: after `retry_deadline`, and before the
+/// shortest `lease` granted at `renewed_at` runs out.
+fn retry_deadline(config: &Config, renewed_at: Instant, lease: Option<u32>) -> Instant {
+    let deadline = Instant::now() + Duration::from_secs(config.retry_deadline.into());
+    // A lease may be too long for `Instant`, e.g. u32::MAX seconds.
+    match lease.and_then(|lease| renewed_at.checked_add(Duration::from_secs(lease.into()))) {
+        Some(lease_end) => deadline.min(lease_end),
+        None => deadline,
+    }
+}
+
+/// Creates the mappings and renews them until `shutdown` is notified. Returns
+/// the error that made creating or renewing a mapping fail for good.
+///
+/// Every mapping made is added to `created`, for cleanup to remove.
+async fn open_and_keep_active(
+    gateway: watch::Sender<Arc<dyn PortMapper>>,
+    config: Config,
+    shutdown: Arc<Notify>,
+    created: Arc<Mutex<Vec<CreatedMapping>>>,
+) -> Result<(), igd::AddPortError> {
+    let mappings = &config.mappings;
+    let current = gateway.borrow().clone();
+    let mut local_ip = current_local_ip(&config, current.as_ref()).unwrap_or_else(|e| {
+        eprintln!("Failed to get local IP: {}", e);
+        process::exit(1);
+    });
+    // Add the mappings. Failing here is most likely a configuration problem,
+    // so it isn't retried. A shutdown still removes those added so far.
+    tokio::select! {
+        _ = shutdown.notified() => return Ok(()),
+        result = add_or_renew_all(&gateway, &created, local_ip, &config, true, None) => result?,
+    }
+    let mut renewed_at = Instant::now();
+    let mut lease = shortest_lease(&created);
+    let mut interval = renewal_interval(&config, lease);
+
+    // Asked after mapping, since PCP only reports it with a mapping.
+    let current = gateway.borrow().clone();
+    let result = tokio::select! {
+        _ = shutdown.notified() => return Ok(()),
+        result = time::timeout(REQUEST_TIMEOUT, current.external_ip()) => {
+            result.unwrap_or_else(|_| Err(igd::GetExternalIpError::RequestError(timed_out())))
+        }
+    };
+    let mut external_ip = result.map_err(|e| {
+        eprintln!("Failed to get external IP: {}", e);
+        match e {
+            igd::GetExternalIpError::ActionNotAuthorized => igd::AddPortError::ActionNotAuthorized,
+            igd::GetExternalIpError::RequestError(e) => igd::AddPortError::RequestError(e),
+        }
+    })?;
+
+    println!();
+    println!("Port forwarding is active.");
+    print_endpoints(mappings, local_ip, external_ip);
+    if lease.is_none() {
+        println!("\nLeases are permanent. The mappings are removed on exit.");
+    }
+    print_renewal_interval(&config, lease, interval);
+    println!();
+    println!("Press Ctrl+C to terminate.");
+
+    loop {
+        tokio::select! {
+            _ = shutdown.notified() => return Ok(()),
+            _ = time::sleep(interval) => {}
+        }
+
+        // Follow the device to its new address, e.g. after a DHCP change or a
+        // switch from Wi-Fi to Ethernet.
+        let current = gateway.borrow().clone();
+        match current_local_ip(&config, current.as_ref()) {
+            Ok(ip) if ip != local_ip => {
+                println!("Local IP changed from {} to {}.", local_ip, ip);
+                // Mappings for other devices stay where they are.
+                let moved: Vec<CreatedMapping> = created
+                    .lock()
+                    .unwrap()
+                    .extract_if(.., |m| !m.other_device)
+                    .collect();
+                tokio::select! {
+                    _ = shutdown.notified() => return Ok(()),
+                    _ = cleanup_ports(current.as_ref(), &moved) => {}
+                }
+                local_ip = ip;
+                println!("Forwarding to {} from now on.", local_ip);
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Failed to get local IP: {}. Keeping {}.", e, local_ip),
+        }
+
+        let started_at = Instant::now();
+        let retry_until = retry_deadline(&config, renewed_at, lease);
+        tokio::select! {
+            _ = shutdown.notified() => return Ok(()),
+            result = add_or_renew_all(&gateway, &created, local_ip, &config, false, Some(retry_until)) => {
+                result?
+            }
+        }
+        renewed_at = started_at;
+        lease = shortest_lease(&created);
+        let next_interval = renewal_interval(&config, lease);
+        if next_interval != interval {
+            interval = next_interval;
+            print_renewal_interval(&config, lease, interval);
+        }
+
+        // The ISP may hand out a new public address at any time.
+        let current = gateway.borrow().clone();
+        let result = tokio::select! {
+            _ = shutdown.notified() => return Ok(()),
+            result = time::timeout(REQUEST_TIMEOUT, current.external_ip()) => {
+                result.unwrap_or_else(|_| Err(igd::GetExternalIpError::RequestError(timed_out())))
+            }
+        };
+        match result {
+            Ok(ip) if ip != external_ip => {
+                println!("External address changed from {} to {}.", external_ip, ip);
+                external_ip = ip;
+                print_endpoints(mappings, local_ip, external_ip);
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Failed to get external IP: {}", e),
+        }
+    }
+}
+
+/// Tells when renewals come sooner than `renewal_interval` because the gateway
+/// granted a shorter `lease`.
+fn print_renewal_interval(config: &Config, lease: Option<u32>, interval: Duration) {
+    if let Some(lease) = lease.filter(|_| interval.as_secs() < config.renewal_interval.into()) {
+        println!(
+            "The gateway granted a lease of {}s. Renewing every {}s.",
+            lease,
+            interval.as_secs()
+        );
+    }
+}
+
+/// Prints where each mapping can be reached from outside.
+fn print_endpoints(mappings: &[Mapping], local_ip: Ipv4Addr, external_ip: IpAddr) {
+    for mapping in mappings {
+        if !mapping.description.is_empty() {
+            let protocol = mapping.protocol.igd_protocols()[0];
+            let ports = mapping.ports()[0];
+            println!("\n{}", expand_description(mapping, protocol, ports));
+        }
+        println!("\nLocal IP -> External IP:");
+        for (device_port, router_port) in mapping.ports() {
+            println!(
+                "{}:{} -> {}:{}",
+                mapping.host.unwrap_or(local_ip),
+                device_port,
+                external_ip,
+                router_port
+            );
+        }
+    }
+}
+
+/// Removes the mappings in `created`, with their pinholes. A removal the
+/// gateway doesn't answer within `REQUEST_TIMEOUT` is given up on.
+async fn cleanup_ports(gateway: &dyn PortMapper, created: &[CreatedMapping]) {
+    for &CreatedMapping {
+        protocol,
+        router_port,
+        local_addr,
+        lease,
+        ipv6,
+        ..
+    } in created
+    {
+        let device_port = local_addr.port();
+        if let Some(firewall) = gateway.firewall().filter(|_| ipv6) {
+            let result = time::timeout(REQUEST_TIMEOUT, firewall.remove(protocol, device_port))
+                .await
+                .unwrap_or_else(|_| Err(igd::RemovePortError::RequestError(timed_out())));
+            match result {
+                Ok(()) => println!("{} pinhole {} closed.", protocol, device_port),
+                // Never opened, e.g. without an IPv6 address.
+                Err(igd::RemovePortError::NoSuchPortMapping) => {}
+                Err(e) => eprintln!(
+                    "Failed to close {} pinhole {}: {}",
+                    protocol, device_port, e
+                ),
+            }
+        }
+        let result = time::timeout(
+            REQUEST_TIMEOUT,
+            gateway.remove(protocol, router_port, local_addr),
+        )
+        .await
+        .unwrap_or_else(|_| Err(igd::RemovePortError::RequestError(timed_out())));
+        match result {
+            Ok(_) => println!(
+                "{} port mapping {} removed successfully.",
+                protocol, router_port
+            ),
+            Err(e) if lease == 0 => eprintln!(
+                "Failed to remove permanent {} port mapping {}: {}. \
+                It stays open until removed in the router's settings.",
+                protocol, router_port, e
+            ),
+            Err(e) => eprintln!(
+                "Failed to remove {} port mapping {}: {}",
+                protocol, router_port, e
+            ),
+        }
+    }
+}
+
+/// Removes the mappings this run created, giving up after `CLEANUP_TIMEOUT`.
+async fn shutdown_program(gateway: &dyn PortMapper, created: &[CreatedMapping]) {
+    println!("Shutting down...");
+    if created.iter().any(|m| m.lease == 0) {
+        println!("Leases are permanent. Removing the mappings is mandatory.");
+    }
+    let cleanup = cleanup_ports(gateway, created);
+    if time::timeout(CLEANUP_TIMEOUT, cleanup).await.is_err() {
+        eprintln!(
+            "Cleanup did not finish within {} seconds.",
+            CLEANUP_TIMEOUT.as_secs()
+        );
+    }
 }
 
 // #[tokio::main]
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
-    let config_path = match get_config_path() {
-        Ok(path) => path,
-        Err(e) => {
-            eprintln!("Error getting current directory: {}", e);
-            process::exit(1);
-        }
-    };
+    let cli = Cli::parse();
 
-    let config = Config::load_or_create(&config_path).unwrap();
+    let config_path = match cli.config.clone() {
+        Some(path) => path,
+        None => match get_config_path() {
+            Ok(path) => path,
+            Err(e) => {
+                eprintln!("Error getting current directory: {}", e);
+                process::exit(1);
+            }
+        },
+    };
 
-    let device_port = config.device_port;
-    let external_port = config.router_port;
+    // Defaults, then the config file, then the environment, then the command line
+    let one_off = cli.is_one_off() || config::env_defines_mapping();
+    let loaded = if !one_off {
+        Config::load_or_create(&config_path, cli.is_interactive())
+    } else if config_path.exists() {
+        Config::load(&config_path)
+    } else {
+        Ok(Config::default())
+    };
+    let mut config = loaded.unwrap_or_else(|e| exit_invalid_config(&config_path, e));
+    if let Err(e) = config.apply_env() {
+        exit_invalid_config(&config_path, e.into());
+    }
+    cli.apply(&mut config);
+    if let Err(e) = config.validate() {
+        exit_invalid_config(&config_path, e.into());
+    }
 
     // Discover the gateway
-    let gateway = match search_gateway(Default::default()) {
+    let gateway = match discover_gateway(&config).await {
         Ok(gw) => gw,
         Err(e) => {
             eprintln!("Failed to discover gateway: {}", e);
@@ -189,63 +885,590 @@ async fn main() {
         }
     };
 
-    // register_windows_console_ctrl_handler(|| {
-    //     thread::sleep(Duration::from_secs(2));
-    //     keep_active_handle.abort();
-    //     cleanup_ports(gateway, external_port);
-    // });
+    // Termination only requests a shutdown. The renewal loop stops at its next
+    // wait and cleanup runs below, on this runtime.
+    let shutdown = Arc::new(Notify::new());
+    let shutdown_clone = shutdown.clone();
+    register_termination_handler(move || {
+        shutdown_clone.notify_one();
+    });
+
+    // The maintainer swaps in a new gateway when the router moves.
+    let (gateway_tx, gateway_rx) = watch::channel(gateway);
+    // Only what this run created is removed on exit.
+    let created = Arc::new(Mutex::new(Vec::new()));
+    let task_connection = task::spawn(open_and_keep_active(
+        gateway_tx,
+        config,
+        shutdown.clone(),
+        created.clone(),
+    ));
+    let failed = match task_connection.await {
+        Ok(Ok(())) => false,
+        Ok(Err(e)) => {
+            eprintln!("Port forwarding stopped: {}", e);
+            true
+        }
+        Err(e) => {
+            eprintln!("Port forwarding stopped unexpectedly: {}", e);
+            true
+        }
+    };
+
+    let gateway = gateway_rx.borrow().clone();
+    let created = created.lock().unwrap().clone();
+    shutdown_program(gateway.as_ref(), &created).await;
+    if failed {
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Protocol;
+    use test_support::{Entry, Failure, Igd, Pinhole, EXTERNAL_IP, SEARCH};
 
-    // Two issues:
-    //
+    const TCP: PortMappingProtocol = PortMappingProtocol::TCP;
+    const UDP: PortMappingProtocol = PortMappingProtocol::UDP;
 
-    // register_windows_console_ctrl_handler(|| {
-    //     thread::sleep(Duration::from_secs(4));
-    // });
+    /// Finds `igd` with SSDP on loopback and maps TCP port 8080 to
+    /// 127.0.0.1.
+    fn config(igd: &Igd) -> Config {
+        let mut config = Config::default();
+        config.backend = Backend::Upnp;
+        config.bind_address = Ipv4Addr::LOCALHOST.to_string();
+        config.broadcast_address = igd.ssdp_addr.to_string();
+        config.discovery_timeout = 1;
+        config.internal_ip = InternalIp::Fixed(Ipv4Addr::LOCALHOST);
+        // The shortest retry delay validate() allows.
+        config.retry_initial_delay = 1;
+        config.mappings = vec![Mapping {
+            device_port: 8080,
+            protocol: Protocol::Tcp,
+            ..Default::default()
+        }];
+        config.validate().unwrap();
+        config
+    }
+
+    /// A mapping of another device on the network.
+    fn other_device() -> Entry {
+        Entry {
+            internal_client: Ipv4Addr::new(192, 168, 1, 20),
+            internal_port: 80,
+            lease_duration: 0,
+            description: "NAS".to_string(),
+        }
+    }
 
-    let future_connection = open_and_keep_active(gateway.clone(), device_port, external_port);
-    let task_connection = DeferredTask::new(future_connection);
-    TASK_OPEN_AND_MAINTAIN_CONNECTION
-        .set(Arc::new(Mutex::new(task_connection)))
+    async fn discover(config: &Config) -> watch::Sender<Arc<dyn PortMapper>> {
+        let gateway = discover_gateway(config).await.unwrap();
+        watch::channel(gateway).0
+    }
+
+    #[tokio::test]
+    async fn discovers_maps_renews_and_cleans_up() {
+        let igd = Igd::start().await;
+        let mut config = config(&igd);
+        config.renewal_interval = 1;
+        let gateway = discover(&config).await;
+        assert_eq!(igd.count(SEARCH), 1);
+
+        let shutdown = Arc::new(Notify::new());
+        let gateway_rx = gateway.subscribe();
+        let created = Arc::new(Mutex::new(Vec::new()));
+        let task = task::spawn(open_and_keep_active(
+            gateway,
+            config,
+            shutdown.clone(),
+            created.clone(),
+        ));
+        igd.wait_for("GetExternalIPAddress", 2).await;
+        let entry = igd.mapping(TCP, 8080).unwrap();
+        assert_eq!(entry.internal_client, Ipv4Addr::LOCALHOST);
+        assert_eq!(entry.internal_port, 8080);
+        assert_eq!(entry.lease_duration, config::DEFAULT_LEASE_TIME);
+        assert_eq!(entry.description, "Rust UPnP Port Forwarder - TCP");
+
+        shutdown.notify_one();
+        task.await.unwrap().unwrap();
+        let gateway = gateway_rx.borrow().clone();
+        let created = created.lock().unwrap().clone();
+        shutdown_program(gateway.as_ref(), &created).await;
+
+        assert_eq!(igd.mapping(TCP, 8080), None);
+        let calls = igd.calls();
+        assert_eq!(
+            calls[..5],
+            [
+                SEARCH,
+                "AddPortMapping",
+                "GetExternalIPAddress",
+                "AddPortMapping",
+                "GetExternalIPAddress"
+            ]
+        );
+        assert_eq!(calls.last().unwrap(), "DeletePortMapping");
+    }
+
+    #[tokio::test]
+    async fn external_ip_is_the_gateways() {
+        let igd = Igd::start().await;
+        let gateway = discover(&config(&igd)).await;
+
+        let external_ip = gateway.borrow().clone().external_ip().await.unwrap();
+        assert_eq!(external_ip, IpAddr::V4(EXTERNAL_IP));
+    }
+
+    #[tokio::test]
+    async fn silent_gateway_is_not_found() {
+        let igd = Igd::start().await;
+        igd.fail(SEARCH, Failure::Timeout);
+
+        assert!(discover_gateway(&config(&igd)).await.is_err());
+        assert_eq!(igd.calls(), [SEARCH]);
+    }
+
+    #[tokio::test]
+    async fn port_of_another_device_is_left_alone() {
+        let igd = Igd::start().await;
+        let config = config(&igd);
+        let other = other_device();
+        igd.insert(TCP, 8080, other.clone());
+        let gateway = discover_gateway(&config).await.unwrap();
+
+        let result = add_or_renew_port(
+            gateway.as_ref(),
+            TCP,
+            Ipv4Addr::LOCALHOST,
+            &config.mappings[0],
+            (8080, 8080),
+            config.lease_time,
+            true,
+        )
+        .await;
+        assert!(matches!(result, Err(igd::AddPortError::PortInUse)));
+        assert_eq!(igd.mapping(TCP, 8080), Some(other));
+        // The conflict is looked up to name the other device.
+        assert_eq!(igd.count("GetGenericPortMappingEntry"), 2);
+    }
+
+    #[tokio::test]
+    async fn only_created_mappings_are_removed_on_exit() {
+        let igd = Igd::start().await;
+        let mut config = config(&igd);
+        config.mappings[0].protocol = Protocol::Both;
+        igd.insert(UDP, 8080, other_device());
+        let gateway = discover(&config).await;
+        let gateway_rx = gateway.subscribe();
+
+        let created = Arc::new(Mutex::new(Vec::new()));
+        let shutdown = Arc::new(Notify::new());
+        let result = open_and_keep_active(gateway, config, shutdown, created.clone()).await;
+        assert!(matches!(result, Err(igd::AddPortError::PortInUse)));
+        let gateway = gateway_rx.borrow().clone();
+        let created = created.lock().unwrap().clone();
+        shutdown_program(gateway.as_ref(), &created).await;
+
+        assert_eq!(igd.mapping(TCP, 8080), None);
+        assert_eq!(igd.mapping(UDP, 8080), Some(other_device()));
+        assert_eq!(igd.count("DeletePortMapping"), 1);
+    }
+
+    /// Runs the mappings until `action` was called `count` times, then shuts
+    /// down. Returns the gateway, the created mappings and how long the run
+    /// took to stop.
+    async fn shut_down_after(
+        igd: &Igd,
+        config: Config,
+        action: &str,
+        count: usize,
+    ) -> (Arc<dyn PortMapper>, Vec<CreatedMapping>, Duration) {
+        let gateway = discover(&config).await;
+        let gateway_rx = gateway.subscribe();
+        let created = Arc::new(Mutex::new(Vec::new()));
+        let shutdown = Arc::new(Notify::new());
+
+        let run = open_and_keep_active(gateway, config, shutdown.clone(), created.clone());
+        let stop = async {
+            igd.wait_for(action, count).await;
+            shutdown.notify_one();
+            Instant::now()
+        };
+        let (result, stopped_at) = tokio::join!(run, stop);
+        assert!(result.is_ok(), "{:?}", result);
+        let gateway = gateway_rx.borrow().clone();
+        let created = created.lock().unwrap().clone();
+        (gateway, created, stopped_at.elapsed())
+    }
+
+    #[tokio::test]
+    async fn shutdown_interrupts_first_mapping_pass() {
+        let igd = Igd::start().await;
+        let mut config = config(&igd);
+        config.mappings[0].protocol = Protocol::Both;
+        igd.pass("AddPortMapping");
+        igd.fail("AddPortMapping", Failure::Timeout);
+
+        let (gateway, created, waited) = shut_down_after(&igd, config, "AddPortMapping", 2).await;
+        assert!(waited < Duration::from_secs(1));
+        shutdown_program(gateway.as_ref(), &created).await;
+        assert_eq!(igd.mapping(TCP, 8080), None);
+        assert_eq!(igd.count("DeletePortMapping"), 1);
+    }
+
+    #[tokio::test]
+    async fn shutdown_interrupts_first_external_ip_request() {
+        let igd = Igd::start().await;
+        let config = config(&igd);
+        igd.fail("GetExternalIPAddress", Failure::Timeout);
+
+        let (gateway, created, waited) =
+            shut_down_after(&igd, config, "GetExternalIPAddress", 1).await;
+        assert!(waited < Duration::from_secs(1));
+        shutdown_program(gateway.as_ref(), &created).await;
+        assert_eq!(igd.mapping(TCP, 8080), None);
+    }
+
+    #[tokio::test]
+    async fn unanswered_external_ip_poll_keeps_renewing() {
+        let igd = Igd::start().await;
+        let mut config = config(&igd);
+        config.renewal_interval = 1;
+        igd.pass("GetExternalIPAddress");
+        igd.fail("GetExternalIPAddress", Failure::Timeout);
+
+        let (_, created, _) = shut_down_after(&igd, config, "AddPortMapping", 3).await;
+        assert_eq!(created.len(), 1);
+        // Renewed after the unanswered poll.
+        assert!(igd.count("GetExternalIPAddress") >= 2);
+    }
+
+    #[tokio::test]
+    async fn permanent_lease_is_requested_after_error_725() {
+        let igd = Igd::start().await;
+        let config = config(&igd);
+        let gateway = discover_gateway(&config).await.unwrap();
+        igd.fail("AddPortMapping", Failure::Error(725));
+
+        let add = |gateway: Arc<dyn PortMapper>, first_run| {
+            let mapping = config.mappings[0].clone();
+            async move {
+                add_or_renew_port(
+                    gateway.as_ref(),
+                    TCP,
+                    Ipv4Addr::LOCALHOST,
+                    &mapping,
+                    (8080, 8080),
+                    3600,
+                    first_run,
+                )
+                .await
+            }
+        };
+        assert_eq!(add(gateway.clone(), true).await.unwrap(), 0);
+        assert_eq!(igd.count("AddPortMapping"), 2);
+        assert_eq!(igd.mapping(TCP, 8080).unwrap().lease_duration, 0);
+
+        // Renewals ask for a permanent lease right away.
+        assert_eq!(add(gateway, false).await.unwrap(), 0);
+        assert_eq!(igd.count("AddPortMapping"), 3);
+
+        // A gateway found again starts with timed leases.
+        let gateway = discover_gateway(&config).await.unwrap();
+        assert_eq!(add(gateway, false).await.unwrap(), 3600);
+        assert_eq!(igd.mapping(TCP, 8080).unwrap().lease_duration, 3600);
+    }
+
+    #[tokio::test]
+    async fn failed_renewal_is_retried() {
+        let igd = Igd::start().await;
+        let config = config(&igd);
+        let gateway = discover(&config).await;
+        let created = Mutex::new(Vec::new());
+        add_or_renew_all(&gateway, &created, Ipv4Addr::LOCALHOST, &config, true, None)
+            .await
+            .unwrap();
+        igd.fail("AddPortMapping", Failure::Error(501));
+        igd.fail("AddPortMapping", Failure::Error(501));
+
+        let retry_until = Instant::now() + Duration::from_secs(10);
+        add_or_renew_all(
+            &gateway,
+            &created,
+            Ipv4Addr::LOCALHOST,
+            &config,
+            false,
+            Some(retry_until),
+        )
+        .await
         .unwrap();
+        assert_eq!(igd.count("AddPortMapping"), 4);
+        assert_eq!(igd.count(SEARCH), 1);
+        assert!(igd.mapping(TCP, 8080).is_some());
+    }
 
-    // Register cleanups
-    let gateway_clone = gateway.clone();
-    std::panic::set_hook(Box::new(move |_| {
-        tokio::runtime::Handle::current().block_on(async {
-            shutdown_program(gateway_clone.clone(), external_port);
-        });
-    }));
-    let gateway_clone = gateway.clone();
-    register_windows_console_ctrl_handler(move || {
-        shutdown_program(gateway_clone.clone(), external_port);
-    });
+    #[tokio::test]
+    async fn failed_first_mapping_is_not_retried() {
+        let igd = Igd::start().await;
+        let config = config(&igd);
+        let gateway = discover(&config).await;
+        igd.fail("AddPortMapping", Failure::Error(501));
+
+        let created = Mutex::new(Vec::new());
+        let result =
+            add_or_renew_all(&gateway, &created, Ipv4Addr::LOCALHOST, &config, true, None).await;
+        assert!(result.is_err());
+        assert_eq!(igd.count("AddPortMapping"), 1);
+    }
+
+    #[tokio::test]
+    async fn unreachable_gateway_is_rediscovered() {
+        let igd = Igd::start().await;
+        let config = config(&igd);
+        let gateway = discover(&config).await;
+        let created = Mutex::new(Vec::new());
+        add_or_renew_all(&gateway, &created, Ipv4Addr::LOCALHOST, &config, true, None)
+            .await
+            .unwrap();
+        igd.fail("AddPortMapping", Failure::Disconnect);
+
+        let retry_until = Instant::now() + Duration::from_secs(10);
+        add_or_renew_all(
+            &gateway,
+            &created,
+            Ipv4Addr::LOCALHOST,
+            &config,
+            false,
+            Some(retry_until),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            igd.calls(),
+            [
+                SEARCH,
+                "AddPortMapping",
+                "AddPortMapping",
+                SEARCH,
+                // The same gateway still has the other mappings.
+                "AddPortMapping"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn moved_gateway_gets_every_mapping_again() {
+        let old_igd = Igd::start().await;
+        let mut config = config(&old_igd);
+        config.mappings[0].protocol = Protocol::Both;
+        let gateway = discover(&config).await;
+        // The router moved to another address.
+        let igd = Igd::start().await;
+        config.broadcast_address = igd.ssdp_addr.to_string();
+        let created = Mutex::new(Vec::new());
+        old_igd.pass("AddPortMapping");
+        old_igd.fail("AddPortMapping", Failure::Disconnect);
+
+        let retry_until = Instant::now() + Duration::from_secs(10);
+        add_or_renew_all(
+            &gateway,
+            &created,
+            Ipv4Addr::LOCALHOST,
+            &config,
+            false,
+            Some(retry_until),
+        )
+        .await
+        .unwrap();
+        assert_eq!(old_igd.count("AddPortMapping"), 2);
+        // UDP on the new gateway, then the pass starts over with TCP.
+        assert_eq!(igd.count("AddPortMapping"), 3);
+        assert!(igd.mapping(TCP, 8080).is_some());
+        assert!(igd.mapping(UDP, 8080).is_some());
+    }
+
+    #[tokio::test]
+    async fn unanswered_renewal_is_rediscovered() {
+        let igd = Igd::start().await;
+        let config = config(&igd);
+        let gateway = discover(&config).await;
+        let created = Mutex::new(Vec::new());
+        add_or_renew_all(&gateway, &created, Ipv4Addr::LOCALHOST, &config, true, None)
+            .await
+            .unwrap();
+        igd.fail("AddPortMapping", Failure::Timeout);
+
+        let started_at = Instant::now();
+        let retry_until = started_at + REQUEST_TIMEOUT * 3;
+        add_or_renew_all(
+            &gateway,
+            &created,
+            Ipv4Addr::LOCALHOST,
+            &config,
+            false,
+            Some(retry_until),
+        )
+        .await
+        .unwrap();
+        assert!(started_at.elapsed() < REQUEST_TIMEOUT + Duration::from_secs(2));
+        assert_eq!(
+            igd.calls(),
+            [
+                SEARCH,
+                "AddPortMapping",
+                "AddPortMapping",
+                SEARCH,
+                "AddPortMapping"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn unanswered_removal_is_given_up_on() {
+        let igd = Igd::start().await;
+        let mut config = config(&igd);
+        config.mappings[0].protocol = Protocol::Both;
+        let gateway = discover(&config).await;
+        let created = Mutex::new(Vec::new());
+        add_or_renew_all(&gateway, &created, Ipv4Addr::LOCALHOST, &config, true, None)
+            .await
+            .unwrap();
+        igd.fail("DeletePortMapping", Failure::Timeout);
+
+        let created = created.lock().unwrap().clone();
+        let gateway = gateway.borrow().clone();
+        cleanup_ports(gateway.as_ref(), &created).await;
+        assert!(igd.mapping(TCP, 8080).is_some());
+        assert_eq!(igd.mapping(UDP, 8080), None);
+    }
+
+    /// A global IPv6 address for this device, which tests can't rely on
+    /// having.
+    const LOCAL_IPV6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x20);
+
+    /// A config whose mapping also opens an IPv6 pinhole.
+    fn ipv6_config(igd: &Igd) -> Config {
+        let mut config = config(igd);
+        config.mappings[0].ipv6 = true;
+        config
+    }
+
+    #[tokio::test]
+    async fn pinhole_is_opened_renewed_and_closed() {
+        let igd = Igd::start().await;
+        let config = ipv6_config(&igd);
+        let gateway = discover_gateway(&config).await.unwrap();
+        let pinhole = |lease_time| Pinhole {
+            internal_client: LOCAL_IPV6,
+            internal_port: 8080,
+            protocol: 6,
+            lease_time,
+        };
 
-    // Start the connection task
-    TASK_OPEN_AND_MAINTAIN_CONNECTION
-        .get()
-        .unwrap()
-        .lock()
-        .unwrap()
-        .start();
-
-    // Handle both Ctrl+C and terminal close
-    // #[cfg(target_family = "unix")]
-    // let mut signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
-    //     .expect("Failed to create signal handler");
-
-    // #[cfg(windows)]
-    // let mut close_handler =
-    //     tokio::signal::windows::ctrl_close().expect("Failed to create ctrl_close signal handler");
-    // #[cfg(windows)]
-    // let close_signal = close_handler.recv();
-
-    // let ctrl_c_signal = tokio::signal::ctrl_c();
-
-    // tokio::select! {
-    //     _ = close_signal  => {}
-    //     _ = ctrl_c_signal => {}
-    // }
-
-    // Keep the program running. Shutdown handled by the ConsoleCtrlHandler
-    tokio::time::sleep(Duration::from_secs(u64::MAX)).await;
+        let open = |first_run| {
+            add_or_renew_pinhole(
+                gateway.as_ref(),
+                TCP,
+                &Ok(LOCAL_IPV6),
+                &config,
+                8080,
+                first_run,
+            )
+        };
+        open(true).await.unwrap();
+        assert_eq!(igd.pinholes(), [pinhole(3600)]);
+
+        open(false).await.unwrap();
+        assert_eq!(igd.count("UpdatePinhole"), 1);
+        assert_eq!(igd.count("AddPinhole"), 1);
+
+        // A rebooted router forgot it, so it is opened again.
+        igd.forget_pinholes();
+        open(false).await.unwrap();
+        assert_eq!(igd.count("AddPinhole"), 2);
+        assert_eq!(igd.pinholes(), [pinhole(3600)]);
+
+        let created = [CreatedMapping {
+            protocol: TCP,
+            router_port: 8080,
+            local_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 8080),
+            lease: 3600,
+            ipv6: true,
+            other_device: false,
+        }];
+        cleanup_ports(gateway.as_ref(), &created).await;
+        assert_eq!(igd.count("DeletePinhole"), 1);
+        assert_eq!(igd.pinholes(), []);
+    }
+
+    #[tokio::test]
+    async fn permanent_lease_opens_longest_pinhole() {
+        let igd = Igd::start().await;
+        let mut config = ipv6_config(&igd);
+        config.lease_time = 0;
+        let gateway = discover_gateway(&config).await.unwrap();
+
+        add_or_renew_pinhole(gateway.as_ref(), UDP, &Ok(LOCAL_IPV6), &config, 9000, true)
+            .await
+            .unwrap();
+        let pinholes = igd.pinholes();
+        assert_eq!(pinholes[0].protocol, 17);
+        assert_eq!(pinholes[0].lease_time, MAX_PINHOLE_LEASE);
+    }
+
+    #[tokio::test]
+    async fn refused_pinhole_keeps_ipv4_mapping() {
+        for code in [606, 701] {
+            let igd = Igd::start().await;
+            let config = ipv6_config(&igd);
+            let gateway = discover_gateway(&config).await.unwrap();
+            igd.fail("AddPinhole", Failure::Error(code));
+
+            let mapping = &config.mappings[0];
+            add_or_renew_port(
+                gateway.as_ref(),
+                TCP,
+                Ipv4Addr::LOCALHOST,
+                mapping,
+                (8080, 8080),
+                3600,
+                true,
+            )
+            .await
+            .unwrap();
+            add_or_renew_pinhole(gateway.as_ref(), TCP, &Ok(LOCAL_IPV6), &config, 8080, true)
+                .await
+                .unwrap();
+            assert!(igd.mapping(TCP, 8080).is_some());
+            assert_eq!(igd.pinholes(), []);
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_gives_up_on_unresponsive_gateway() {
+        let igd = Igd::start().await;
+        let config = config(&igd);
+        let gateway = discover_gateway(&config).await.unwrap();
+        let created = Mutex::new(Vec::new());
+        add_or_renew_all(
+            &watch::channel(gateway.clone()).0,
+            &created,
+            Ipv4Addr::LOCALHOST,
+            &config,
+            true,
+            None,
+        )
+        .await
+        .unwrap();
+        igd.fail("DeletePortMapping", Failure::Timeout);
+
+        let started_at = Instant::now();
+        let created = created.lock().unwrap().clone();
+        shutdown_program(gateway.as_ref(), &created).await;
+        assert!(started_at.elapsed() < CLEANUP_TIMEOUT + Duration::from_secs(1));
+        assert_eq!(igd.count("DeletePortMapping"), 1);
+        assert!(igd.mapping(TCP, 8080).is_some());
+    }
 }
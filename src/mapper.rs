@@ -0,0 +1,182 @@
+// src/mapper.rs
+use crate::gateway::IgdGateway;
+use crate::pinhole::Firewall;
+use async_trait::async_trait;
+use igd::{
+    AddPortError, GetExternalIpError, GetGenericPortMappingEntryError, PortMappingEntry,
+    PortMappingProtocol, RemovePortError, RequestError,
+};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A router that maps ports, through whichever protocol it answered. Errors
+/// use igd's types, with the other protocols' result codes mapped onto them.
+#[async_trait]
+pub trait PortMapper: fmt::Display + Send + Sync {
+    /// Address the router is contacted at.
+    fn addr(&self) -> SocketAddr;
+
+    /// Maps `external_port` to `local_addr`. A `lease_duration` of 0 asks for
+    /// a permanent lease, or the longest one the protocol allows. Returns the
+    /// lease granted, 0 if permanent.
+    async fn add(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddr,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<u32, AddPortError>;
+
+    /// Extends the lease of a mapping made by `add`. Mapping the port again
+    /// does that for every protocol so far.
+    async fn renew(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddr,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<u32, AddPortError> {
+        self.add(
+            protocol,
+            external_port,
+            local_addr,
+            lease_duration,
+            description,
+        )
+        .await
+    }
+
+    /// Removes the mapping of `external_port`. PCP and NAT-PMP identify
+    /// mappings by the `local_addr` they were made for instead.
+    async fn remove(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddr,
+    ) -> Result<(), RemovePortError>;
+
+    async fn external_ip(&self) -> Result<IpAddr, GetExternalIpError>;
+
+    /// Every mapping on the router, including other devices' ones.
+    async fn list(&self) -> Result<Vec<PortMappingEntry>, RequestError> {
+        Err(RequestError::UnsupportedAction(format!(
+            "{} can't list its mappings",
+            self
+        )))
+    }
+
+    /// Where IPv6 pinholes are opened, if the router supports them.
+    fn firewall(&self) -> Option<&Firewall> {
+        None
+    }
+}
+
+/// A UPnP IGD gateway, with its IPv6 firewall control if it has one.
+pub struct Upnp {
+    pub gateway: IgdGateway,
+    pub firewall: Option<Firewall>,
+    /// Set once the gateway rejects a timed lease with error 725. Every later
+    /// request then asks for a permanent lease.
+    permanent_leases_only: AtomicBool,
+}
+
+impl Upnp {
+    pub fn new(gateway: IgdGateway, firewall: Option<Firewall>) -> Self {
+        Upnp {
+            gateway,
+            firewall,
+            permanent_leases_only: AtomicBool::new(false),
+        }
+    }
+}
+
+#[async_trait]
+impl PortMapper for Upnp {
+    fn addr(&self) -> SocketAddr {
+        self.gateway.addr
+    }
+
+    async fn add(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddr,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<u32, AddPortError> {
+        let lease_duration = if self.permanent_leases_only.load(Ordering::SeqCst) {
+            0
+        } else {
+            lease_duration
+        };
+        let result = self
+            .gateway
+            .add_port(
+                protocol,
+                external_port,
+                local_addr,
+                lease_duration,
+                description,
+            )
+            .await;
+        match result {
+            Err(AddPortError::OnlyPermanentLeasesSupported) if lease_duration != 0 => {
+                println!(
+                    "Gateway only supports permanent leases. Retrying {} port {} with a \
+                    permanent lease.",
+                    protocol, external_port
+                );
+                self.permanent_leases_only.store(true, Ordering::SeqCst);
+                self.gateway
+                    .add_port(protocol, external_port, local_addr, 0, description)
+                    .await
+                    .map(|()| 0)
+            }
+            result => result.map(|()| lease_duration),
+        }
+    }
+
+    async fn remove(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        _local_addr: SocketAddr,
+    ) -> Result<(), RemovePortError> {
+        self.gateway.remove_port(protocol, external_port).await
+    }
+
+    async fn external_ip(&self) -> Result<IpAddr, GetExternalIpError> {
+        self.gateway.get_external_ip().await
+    }
+
+    async fn list(&self) -> Result<Vec<PortMappingEntry>, RequestError> {
+        let mut entries = Vec::new();
+        loop {
+            let index = entries.len() as u32;
+            match self.gateway.get_generic_port_mapping_entry(index).await {
+                Ok(entry) => entries.push(entry),
+                // Past the last entry.
+                Err(GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid) => {
+                    return Ok(entries)
+                }
+                Err(GetGenericPortMappingEntryError::ActionNotAuthorized) => {
+                    return Err(RequestError::ErrorCode(606, "Action not authorized".into()))
+                }
+                Err(GetGenericPortMappingEntryError::RequestError(e)) => return Err(e),
+            }
+        }
+    }
+
+    fn firewall(&self) -> Option<&Firewall> {
+        self.firewall.as_ref()
+    }
+}
+
+impl fmt::Display for Upnp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.gateway.fmt(f)
+    }
+}
@@ -0,0 +1,376 @@
+// src/natpmp.rs
+//! NAT-PMP client (RFC 6886) for routers that don't speak UPnP IGD.
+
+use crate::mapper::PortMapper;
+use crate::wire;
+use async_trait::async_trait;
+use igd::{AddPortError, GetExternalIpError, PortMappingProtocol, RemovePortError, RequestError};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use tokio::net::UdpSocket;
+
+pub const NATPMP_PORT: u16 = 5351;
+
+const OP_EXTERNAL_ADDRESS: u8 = 0;
+const OP_MAP_UDP: u8 = 1;
+const OP_MAP_TCP: u8 = 2;
+
+const RESULT_NOT_AUTHORIZED: u16 = 2;
+const RESULT_OUT_OF_RESOURCES: u16 = 4;
+
+#[derive(Debug, Clone)]
+pub struct NatPmp {
+    pub addr: SocketAddr,
+}
+
+impl NatPmp {
+    /// Checks that `gateway` answers NAT-PMP requests.
+    pub async fn connect(gateway: Ipv4Addr) -> Result<Self, RequestError> {
+        let natpmp = NatPmp {
+            addr: SocketAddr::V4(SocketAddrV4::new(gateway, NATPMP_PORT)),
+        };
+        natpmp.external_address().await?;
+        Ok(natpmp)
+    }
+
+    async fn external_address(&self) -> Result<Ipv4Addr, RequestError> {
+        let socket = self.socket(Ipv4Addr::UNSPECIFIED.into()).await?;
+        let response = request(&socket, &[0, OP_EXTERNAL_ADDRESS], 12).await?;
+        Ok(Ipv4Addr::new(
+            response[8],
+            response[9],
+            response[10],
+            response[11],
+        ))
+    }
+
+    /// A socket sending from `local_ip`, which is the address the router maps
+    /// to.
+    async fn socket(&self, local_ip: IpAddr) -> io::Result<UdpSocket> {
+        let socket = UdpSocket::bind((local_ip, 0)).await?;
+        socket.connect(self.addr).await?;
+        Ok(socket)
+    }
+}
+
+#[async_trait]
+impl PortMapper for NatPmp {
+    fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    async fn external_ip(&self) -> Result<IpAddr, GetExternalIpError> {
+        let ip = self
+            .external_address()
+            .await
+            .map_err(GetExternalIpError::RequestError)?;
+        Ok(IpAddr::V4(ip))
+    }
+
+    /// Maps `external_port` to `local_addr`. NAT-PMP only maps to the host
+    /// that asks, so `local_addr` must be an address of this device.
+    ///
+    /// NAT-PMP has no permanent leases; a `lease_duration` of 0 asks for the
+    /// longest lease the router grants. The router may grant less than asked.
+    async fn add(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddr,
+        lease_duration: u32,
+        _description: &str,
+    ) -> Result<u32, AddPortError> {
+        let lifetime = if lease_duration == 0 {
+            u32::MAX
+        } else {
+            lease_duration
+        };
+        let socket = self.socket(local_addr.ip()).await.map_err(|e| {
+            if e.kind() == io::ErrorKind::AddrNotAvailable {
+                // The address belongs to another device.
+                AddPortError::ActionNotAuthorized
+            } else {
+                AddPortError::RequestError(e.into())
+            }
+        })?;
+        let response = map(
+            &socket,
+            protocol,
+            local_addr.port(),
+            external_port,
+            lifetime,
+        )
+        .await
+        .map_err(|e| match e {
+            RequestError::ErrorCode(RESULT_NOT_AUTHORIZED, _) => AddPortError::ActionNotAuthorized,
+            RequestError::ErrorCode(RESULT_OUT_OF_RESOURCES, _) => AddPortError::PortInUse,
+            e => AddPortError::RequestError(e),
+        })?;
+
+        if response.external_port != external_port {
+            // The router picked another port; give it back.
+            let _ = map(&socket, protocol, local_addr.port(), 0, 0).await;
+            return Err(AddPortError::PortInUse);
+        }
+        Ok(response.lifetime)
+    }
+
+    /// Removes the mapping of `local_addr`'s port. The request comes from
+    /// `local_addr`, since the router only deletes the asking host's mappings.
+    async fn remove(
+        &self,
+        protocol: PortMappingProtocol,
+        _external_port: u16,
+        local_addr: SocketAddr,
+    ) -> Result<(), RemovePortError> {
+        let socket = self.socket(local_addr.ip()).await.map_err(|e| {
+            if e.kind() == io::ErrorKind::AddrNotAvailable {
+                RemovePortError::ActionNotAuthorized
+            } else {
+                RemovePortError::RequestError(e.into())
+            }
+        })?;
+        match map(&socket, protocol, local_addr.port(), 0, 0).await {
+            Ok(_) => Ok(()),
+            Err(RequestError::ErrorCode(RESULT_NOT_AUTHORIZED, _)) => {
+                Err(RemovePortError::ActionNotAuthorized)
+            }
+            Err(e) => Err(RemovePortError::RequestError(e)),
+        }
+    }
+}
+
+impl fmt::Display for NatPmp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NAT-PMP gateway at {}", self.addr)
+    }
+}
+
+struct MapResponse {
+    external_port: u16,
+    lifetime: u32,
+}
+
+/// Sends a mapping request. A `lifetime` of 0 deletes the mapping.
+async fn map(
+    socket: &UdpSocket,
+    protocol: PortMappingProtocol,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: u32,
+) -> Result<MapResponse, RequestError> {
+    let opcode = match protocol {
+        PortMappingProtocol::UDP => OP_MAP_UDP,
+        PortMappingProtocol::TCP => OP_MAP_TCP,
+    };
+    let mut packet = [0; 12];
+    packet[1] = opcode;
+    packet[4..6].copy_from_slice(&internal_port.to_be_bytes());
+    packet[6..8].copy_from_slice(&external_port.to_be_bytes());
+    packet[8..12].copy_from_slice(&lifetime.to_be_bytes());
+
+    let response = request(socket, &packet, 16).await?;
+    Ok(MapResponse {
+        external_port: u16::from_be_bytes([response[10], response[11]]),
+        lifetime: u32::from_be_bytes([response[12], response[13], response[14], response[15]]),
+    })
+}
+
+/// Sends `packet` until a response of `len` bytes to its opcode arrives, and
+/// checks its result code.
+async fn request(socket: &UdpSocket, packet: &[u8], len: usize) -> Result<Vec<u8>, RequestError> {
+    let opcode = packet[1];
+    wire::request(socket, packet, "NAT-PMP gateway", |response| {
+        if response.len() < len || response[0] != 0 || response[1] != 128 + opcode {
+            return None;
+        }
+        Some(match u16::from_be_bytes([response[2], response[3]]) {
+            0 => Ok(response.to_vec()),
+            code => Err(RequestError::ErrorCode(code, result_message(code).into())),
+        })
+    })
+    .await
+}
+
+fn result_message(code: u16) -> &'static str {
+    match code {
+        1 => "Unsupported Version",
+        RESULT_NOT_AUTHORIZED => "Not Authorized/Refused",
+        3 => "Network Failure",
+        RESULT_OUT_OF_RESOURCES => "Out of resources",
+        5 => "Unsupported opcode",
+        _ => "Unknown result code",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    const EXTERNAL_IP: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 7);
+    const MAX_LIFETIME: u32 = 7200;
+
+    /// A NAT-PMP gateway stand-in that grants every request for up to
+    /// `MAX_LIFETIME`, or answers `result` if set, and records the requests
+    /// it got with their senders. It assigns `external_port` instead of the
+    /// requested one if set.
+    struct Server {
+        addr: SocketAddr,
+        requests: Arc<Mutex<Vec<Vec<u8>>>>,
+        senders: Arc<Mutex<Vec<SocketAddr>>>,
+    }
+
+    impl Server {
+        async fn start(result: u16, external_port: Option<u16>) -> Server {
+            let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+            let addr = socket.local_addr().unwrap();
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let senders = Arc::new(Mutex::new(Vec::new()));
+            let (recorded, recorded_senders) = (requests.clone(), senders.clone());
+            tokio::spawn(async move {
+                let mut buffer = [0; 16];
+                loop {
+                    let (len, from) = socket.recv_from(&mut buffer).await.unwrap();
+                    let request = buffer[..len].to_vec();
+                    let mut response = vec![0, 128 + request[1]];
+                    response.extend_from_slice(&result.to_be_bytes());
+                    // Seconds since the gateway started.
+                    response.extend_from_slice(&60u32.to_be_bytes());
+                    if request[1] == OP_EXTERNAL_ADDRESS {
+                        response.extend_from_slice(&EXTERNAL_IP.octets());
+                    } else {
+                        let lifetime = u32::from_be_bytes(request[8..12].try_into().unwrap());
+                        let port = match (lifetime, external_port) {
+                            (0, _) => [0, 0],
+                            (_, Some(port)) => port.to_be_bytes(),
+                            (_, None) => [request[6], request[7]],
+                        };
+                        response.extend_from_slice(&request[4..6]);
+                        response.extend_from_slice(&port);
+                        response.extend_from_slice(&lifetime.min(MAX_LIFETIME).to_be_bytes());
+                    }
+                    recorded.lock().unwrap().push(request);
+                    recorded_senders.lock().unwrap().push(from);
+                    socket.send_to(&response, from).await.unwrap();
+                }
+            });
+            Server {
+                addr,
+                requests,
+                senders,
+            }
+        }
+
+        fn requests(&self) -> Vec<Vec<u8>> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        fn senders(&self) -> Vec<SocketAddr> {
+            self.senders.lock().unwrap().clone()
+        }
+    }
+
+    fn local(port: u16) -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)
+    }
+
+    #[tokio::test]
+    async fn maps_renews_and_deletes() {
+        let server = Server::start(0, None).await;
+        let natpmp = NatPmp { addr: server.addr };
+
+        let tcp = PortMappingProtocol::TCP;
+        assert_eq!(
+            natpmp.add(tcp, 8080, local(80), 3600, "").await.unwrap(),
+            3600
+        );
+        assert_eq!(
+            natpmp.renew(tcp, 8080, local(80), 3600, "").await.unwrap(),
+            3600
+        );
+        natpmp.remove(tcp, 8080, local(80)).await.unwrap();
+
+        let requests = server.requests();
+        let [add, renew, delete] = &requests[..] else {
+            panic!("expected 3 requests, got {}", requests.len());
+        };
+        assert_eq!(add[..2], [0, OP_MAP_TCP]);
+        assert_eq!(add[4..6], 80u16.to_be_bytes());
+        assert_eq!(add[6..8], 8080u16.to_be_bytes());
+        assert_eq!(add[8..12], 3600u32.to_be_bytes());
+        assert_eq!(renew, add);
+        assert_eq!(delete[..2], [0, OP_MAP_TCP]);
+        assert_eq!(delete[4..6], 80u16.to_be_bytes());
+        assert_eq!(delete[6..12], [0; 6]);
+    }
+
+    #[tokio::test]
+    async fn delete_comes_from_the_mapped_address() {
+        let server = Server::start(0, None).await;
+        let natpmp = NatPmp { addr: server.addr };
+        // Not the address the route to the server would pick.
+        let local = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 2).into(), 80);
+
+        let tcp = PortMappingProtocol::TCP;
+        natpmp.add(tcp, 8080, local, 3600, "").await.unwrap();
+        natpmp.remove(tcp, 8080, local).await.unwrap();
+
+        let senders = server.senders();
+        assert_eq!(senders.len(), 2);
+        assert!(senders.iter().all(|sender| sender.ip() == local.ip()));
+    }
+
+    #[tokio::test]
+    async fn longest_lease_is_asked_for_a_permanent_one() {
+        let server = Server::start(0, None).await;
+        let natpmp = NatPmp { addr: server.addr };
+
+        let granted = natpmp
+            .add(PortMappingProtocol::UDP, 9000, local(9000), 0, "")
+            .await;
+        assert_eq!(granted.unwrap(), MAX_LIFETIME);
+        let add = &server.requests()[0];
+        assert_eq!(add[1], OP_MAP_UDP);
+        assert_eq!(add[8..12], u32::MAX.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn other_external_port_is_given_back() {
+        let server = Server::start(0, Some(8081)).await;
+        let natpmp = NatPmp { addr: server.addr };
+
+        let result = natpmp
+            .add(PortMappingProtocol::TCP, 8080, local(80), 3600, "")
+            .await;
+        assert!(matches!(result, Err(AddPortError::PortInUse)));
+        let requests = server.requests();
+        let [_, delete] = &requests[..] else {
+            panic!("expected 2 requests, got {}", requests.len());
+        };
+        assert_eq!(delete[4..6], 80u16.to_be_bytes());
+        assert_eq!(delete[8..12], 0u32.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn refused_mapping_is_not_authorized() {
+        let server = Server::start(RESULT_NOT_AUTHORIZED, None).await;
+        let natpmp = NatPmp { addr: server.addr };
+
+        let result = natpmp
+            .add(PortMappingProtocol::TCP, 8080, local(80), 3600, "")
+            .await;
+        assert!(matches!(result, Err(AddPortError::ActionNotAuthorized)));
+    }
+
+    #[tokio::test]
+    async fn external_address_is_the_gateways() {
+        let server = Server::start(0, None).await;
+        let natpmp = NatPmp { addr: server.addr };
+
+        let external_ip = natpmp.external_ip().await.unwrap();
+        assert_eq!(external_ip, IpAddr::V4(EXTERNAL_IP));
+        assert_eq!(server.requests()[0], [0, OP_EXTERNAL_ADDRESS]);
+    }
+}
@@ -0,0 +1,657 @@
+// src/pcp.rs
+//! Port Control Protocol client (RFC 6887), the successor of NAT-PMP. Unlike
+//! NAT-PMP it also works over IPv6, where a mapping opens the router's
+//! firewall for the device's own address.
+
+use crate::mapper::PortMapper;
+use crate::wire::{self, protocol_number};
+use async_trait::async_trait;
+use igd::{AddPortError, GetExternalIpError, PortMappingProtocol, RemovePortError, RequestError};
+use rand::Rng;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+
+pub const PCP_PORT: u16 = 5351;
+
+const VERSION: u8 = 2;
+const OP_ANNOUNCE: u8 = 0;
+const OP_MAP: u8 = 1;
+const RESPONSE_BIT: u8 = 0x80;
+const OPTION_THIRD_PARTY: u8 = 1;
+
+const HEADER_LEN: usize = 24;
+const MAP_LEN: usize = 36;
+
+const RESULT_NOT_AUTHORIZED: u16 = 2;
+const RESULT_NO_RESOURCES: u16 = 8;
+const RESULT_CANNOT_PROVIDE_EXTERNAL: u16 = 11;
+
+type Nonce = [u8; 12];
+
+#[derive(Debug, Clone)]
+pub struct Pcp {
+    pub addr: SocketAddr,
+    /// The mappings made, by protocol number and internal address and port.
+    mappings: Arc<Mutex<HashMap<(u8, SocketAddr), Mapping>>>,
+    /// PCP has no request for the external address; it comes with every
+    /// mapping.
+    external_ip: Arc<Mutex<Option<IpAddr>>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Mapping {
+    /// The nonce the mapping was created with, which the server requires to
+    /// renew or delete it.
+    nonce: Nonce,
+    /// The device it was requested for with the THIRD_PARTY option.
+    third_party: Option<IpAddr>,
+}
+
+impl Pcp {
+    /// Checks that the server at `addr` answers PCP requests.
+    pub async fn connect(addr: SocketAddr) -> Result<Self, RequestError> {
+        let pcp = Pcp {
+            addr,
+            mappings: Default::default(),
+            external_ip: Default::default(),
+        };
+        let socket = pcp.socket(None).await?;
+        let announce = header(OP_ANNOUNCE, 0, local_ip(&socket)?);
+        request(&socket, &announce, OP_ANNOUNCE, |_| Some(Ok(()))).await?;
+        Ok(pcp)
+    }
+
+    /// A socket sending from `local_ip`, or from the address the system
+    /// picks to reach the server.
+    async fn socket(&self, local_ip: Option<IpAddr>) -> io::Result<UdpSocket> {
+        let local_ip = local_ip.unwrap_or(match self.addr {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        });
+        let socket = UdpSocket::bind((local_ip, 0)).await?;
+        socket.connect(self.addr).await?;
+        Ok(socket)
+    }
+
+    /// A socket to map `local` from, and the address to send in the
+    /// THIRD_PARTY option if `local` belongs to another device.
+    async fn socket_for(&self, local: IpAddr) -> io::Result<(UdpSocket, Option<IpAddr>)> {
+        if local.is_ipv4() != self.addr.is_ipv4() {
+            return Ok((self.socket(None).await?, None));
+        }
+        match self.socket(Some(local)).await {
+            Err(e) if e.kind() == io::ErrorKind::AddrNotAvailable => {
+                Ok((self.socket(None).await?, Some(local)))
+            }
+            socket => Ok((socket?, None)),
+        }
+    }
+}
+
+#[async_trait]
+impl PortMapper for Pcp {
+    fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The external address of the last mapping the server granted.
+    async fn external_ip(&self) -> Result<IpAddr, GetExternalIpError> {
+        let external_ip = *self.external_ip.lock().unwrap();
+        external_ip.ok_or_else(|| {
+            GetExternalIpError::RequestError(RequestError::InvalidResponse(
+                "PCP reports the external address only with a mapping".to_string(),
+            ))
+        })
+    }
+
+    /// Maps `external_port` to `local_addr`. Mappings for another device are
+    /// requested with the THIRD_PARTY option, which servers may refuse.
+    ///
+    /// Over IPv6 the mapping points to this device's IPv6 address, so an IPv4
+    /// `local_addr` must be one of this device. PCP has no permanent leases; a
+    /// `lease_duration` of 0 asks for the longest lease the server grants.
+    /// The server may grant less than asked.
+    async fn add(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        local_addr: SocketAddr,
+        lease_duration: u32,
+        _description: &str,
+    ) -> Result<u32, AddPortError> {
+        let local = local_addr.ip();
+        if local.is_ipv4() != self.addr.is_ipv4() && !is_own(local) {
+            return Err(AddPortError::RequestError(RequestError::UnsupportedAction(
+                format!("{} can't map another device's address {}", self, local),
+            )));
+        }
+        let lifetime = if lease_duration == 0 {
+            u32::MAX
+        } else {
+            lease_duration
+        };
+        let (socket, third_party) = self
+            .socket_for(local_addr.ip())
+            .await
+            .map_err(|e| AddPortError::RequestError(e.into()))?;
+
+        let key = (protocol_number(protocol), local_addr);
+        let nonce = self
+            .mappings
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_insert_with(|| Mapping {
+                nonce: rand::rng().random(),
+                third_party,
+            })
+            .nonce;
+        let map_request = MapRequest {
+            nonce,
+            protocol,
+            internal_port: local_addr.port(),
+            external_port,
+            lifetime,
+            third_party,
+        };
+        let packet = map_request
+            .encode(local_ip(&socket).map_err(|e| AddPortError::RequestError(e.into()))?);
+
+        let response = request(&socket, &packet, OP_MAP, |response| {
+            MapResponse::decode(response, &map_request)
+        })
+        .await
+        .map_err(|e| match e {
+            RequestError::ErrorCode(RESULT_NOT_AUTHORIZED, _) => AddPortError::ActionNotAuthorized,
+            RequestError::ErrorCode(RESULT_NO_RESOURCES | RESULT_CANNOT_PROVIDE_EXTERNAL, _) => {
+                AddPortError::PortInUse
+            }
+            e => AddPortError::RequestError(e),
+        })?;
+
+        *self.external_ip.lock().unwrap() = Some(response.external_ip);
+        if response.external_port != external_port {
+            // The server picked another port; give it back.
+            let _ = self.remove(protocol, 0, local_addr).await;
+            return Err(AddPortError::PortInUse);
+        }
+        Ok(response.lifetime)
+    }
+
+    /// Removes the mapping to `local_addr` created by `add`, on behalf of
+    /// the other device if it was requested for one.
+    async fn remove(
+        &self,
+        protocol: PortMappingProtocol,
+        _external_port: u16,
+        local_addr: SocketAddr,
+    ) -> Result<(), RemovePortError> {
+        let key = (protocol_number(protocol), local_addr);
+        let mapping = self.mappings.lock().unwrap().remove(&key);
+        let Some(mapping) = mapping else {
+            return Err(RemovePortError::NoSuchPortMapping);
+        };
+        let socket = match mapping.third_party {
+            Some(_) => self.socket(None).await,
+            None => self
+                .socket_for(local_addr.ip())
+                .await
+                .map(|(socket, _)| socket),
+        };
+        let socket = socket.map_err(|e| RemovePortError::RequestError(e.into()))?;
+        let map_request = MapRequest {
+            nonce: mapping.nonce,
+            protocol,
+            internal_port: local_addr.port(),
+            external_port: 0,
+            lifetime: 0,
+            third_party: mapping.third_party,
+        };
+        let packet = map_request
+            .encode(local_ip(&socket).map_err(|e| RemovePortError::RequestError(e.into()))?);
+
+        let decode = |response: &[u8]| MapResponse::decode(response, &map_request);
+        match request(&socket, &packet, OP_MAP, decode).await {
+            Ok(_) => Ok(()),
+            Err(RequestError::ErrorCode(RESULT_NOT_AUTHORIZED, _)) => {
+                Err(RemovePortError::ActionNotAuthorized)
+            }
+            Err(e) => Err(RemovePortError::RequestError(e)),
+        }
+    }
+}
+
+impl fmt::Display for Pcp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PCP server at {}", self.addr)
+    }
+}
+
+struct MapRequest {
+    nonce: Nonce,
+    protocol: PortMappingProtocol,
+    internal_port: u16,
+    external_port: u16,
+    /// 0 deletes the mapping.
+    lifetime: u32,
+    /// The device to map to, if it isn't the one sending the request.
+    third_party: Option<IpAddr>,
+}
+
+impl MapRequest {
+    fn encode(&self, client_ip: IpAddr) -> Vec<u8> {
+        let mut packet = header(OP_MAP, self.lifetime, client_ip);
+        packet.extend_from_slice(&self.nonce);
+        packet.push(protocol_number(self.protocol));
+        packet.extend_from_slice(&[0; 3]);
+        packet.extend_from_slice(&self.internal_port.to_be_bytes());
+        packet.extend_from_slice(&self.external_port.to_be_bytes());
+        // No preference for the external address.
+        let any = match client_ip {
+            IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.to_ipv6_mapped(),
+            IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED,
+        };
+        packet.extend_from_slice(&any.octets());
+        if let Some(ip) = self.third_party {
+            packet.extend_from_slice(&[OPTION_THIRD_PARTY, 0, 0, 16]);
+            packet.extend_from_slice(&ipv6_octets(ip));
+        }
+        packet
+    }
+}
+
+struct MapResponse {
+    lifetime: u32,
+    external_port: u16,
+    external_ip: IpAddr,
+}
+
+impl MapResponse {
+    /// Reads the response to `request`. Returns `None` for a response to
+    /// another request, whose nonce, protocol or internal port differ
+    /// (RFC 6887 section 11.4).
+    fn decode(packet: &[u8], request: &MapRequest) -> Option<Result<Self, RequestError>> {
+        if packet.len() < HEADER_LEN + MAP_LEN {
+            return Some(Err(RequestError::InvalidResponse(
+                "short PCP response".to_string(),
+            )));
+        }
+        let payload = &packet[HEADER_LEN..];
+        if payload[..12] != request.nonce
+            || payload[12] != protocol_number(request.protocol)
+            || payload[16..18] != request.internal_port.to_be_bytes()
+        {
+            return None;
+        }
+        let external_ip = Ipv6Addr::from(<[u8; 16]>::try_from(&payload[20..36]).unwrap());
+        Some(Ok(MapResponse {
+            lifetime: u32::from_be_bytes(packet[4..8].try_into().unwrap()),
+            external_port: u16::from_be_bytes([payload[18], payload[19]]),
+            external_ip: external_ip.to_canonical(),
+        }))
+    }
+}
+
+/// The common request header. PCP writes IPv4 addresses as IPv4-mapped IPv6
+/// addresses.
+fn header(opcode: u8, lifetime: u32, client_ip: IpAddr) -> Vec<u8> {
+    let mut packet = vec![VERSION, opcode, 0, 0];
+    packet.extend_from_slice(&lifetime.to_be_bytes());
+    packet.extend_from_slice(&ipv6_octets(client_ip));
+    packet
+}
+
+fn ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+/// Whether `ip` is an address of this device.
+fn is_own(ip: IpAddr) -> bool {
+    std::net::UdpSocket::bind((ip, 0)).is_ok()
+}
+
+/// The address the server sees the request come from, which PCP requires in
+/// the header.
+fn local_ip(socket: &UdpSocket) -> io::Result<IpAddr> {
+    Ok(socket.local_addr()?.ip())
+}
+
+/// Sends `packet` until the matching response arrives, and checks its result
+/// code. `decode` reads the response, or returns `None` if it answers
+/// another request.
+async fn request<T>(
+    socket: &UdpSocket,
+    packet: &[u8],
+    opcode: u8,
+    decode: impl Fn(&[u8]) -> Option<Result<T, RequestError>>,
+) -> Result<T, RequestError> {
+    wire::request(socket, packet, "PCP server", |response| {
+        // A NAT-PMP-only server answers with version 0.
+        if response.len() >= 4 && response[0] == 0 {
+            return Some(Err(RequestError::ErrorCode(1, result_message(1).into())));
+        }
+        if response.len() < HEADER_LEN
+            || response[0] != VERSION
+            || response[1] != RESPONSE_BIT | opcode
+        {
+            return None;
+        }
+        let decoded = decode(response)?;
+        Some(match u16::from(response[3]) {
+            0 => decoded,
+            code => Err(RequestError::ErrorCode(code, result_message(code).into())),
+        })
+    })
+    .await
+}
+
+fn result_message(code: u16) -> &'static str {
+    match code {
+        1 => "UNSUPP_VERSION",
+        RESULT_NOT_AUTHORIZED => "NOT_AUTHORIZED",
+        3 => "MALFORMED_REQUEST",
+        4 => "UNSUPP_OPCODE",
+        5 => "UNSUPP_OPTION",
+        6 => "MALFORMED_OPTION",
+        7 => "NETWORK_FAILURE",
+        RESULT_NO_RESOURCES => "NO_RESOURCES",
+        9 => "UNSUPP_PROTOCOL",
+        10 => "USER_EX_QUOTA",
+        RESULT_CANNOT_PROVIDE_EXTERNAL => "CANNOT_PROVIDE_EXTERNAL",
+        12 => "ADDRESS_MISMATCH",
+        13 => "EXCESSIVE_REMOTE_PEERS",
+        _ => "unknown result code",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXTERNAL_IP: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 7);
+    const MAX_LIFETIME: u32 = 7200;
+
+    /// A PCP server stand-in that grants every request for up to
+    /// `MAX_LIFETIME`, or answers `result` if set, and records the requests
+    /// it got.
+    struct Server {
+        addr: SocketAddr,
+        requests: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl Server {
+        async fn start(ip: IpAddr, result: u8) -> Server {
+            let socket = UdpSocket::bind((ip, 0)).await.unwrap();
+            let addr = socket.local_addr().unwrap();
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let recorded = requests.clone();
+            tokio::spawn(async move {
+                let mut buffer = [0; 1100];
+                loop {
+                    let (len, from) = socket.recv_from(&mut buffer).await.unwrap();
+                    let request = buffer[..len].to_vec();
+                    let mut response = request.clone();
+                    response[1] |= RESPONSE_BIT;
+                    response[3] = result;
+                    if request[1] == OP_MAP {
+                        let lifetime = u32::from_be_bytes(request[4..8].try_into().unwrap());
+                        response[4..8].copy_from_slice(&lifetime.min(MAX_LIFETIME).to_be_bytes());
+                        response[HEADER_LEN + 20..HEADER_LEN + 36]
+                            .copy_from_slice(&EXTERNAL_IP.to_ipv6_mapped().octets());
+                    }
+                    recorded.lock().unwrap().push(request);
+                    socket.send_to(&response, from).await.unwrap();
+                }
+            });
+            Server { addr, requests }
+        }
+
+        fn requests(&self) -> Vec<Vec<u8>> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    fn local(ip: IpAddr, port: u16) -> SocketAddr {
+        SocketAddr::new(ip, port)
+    }
+
+    #[tokio::test]
+    async fn maps_renews_and_deletes_with_one_nonce() {
+        let loopback = Ipv4Addr::LOCALHOST.into();
+        let server = Server::start(loopback, 0).await;
+        let pcp = Pcp::connect(server.addr).await.unwrap();
+
+        let tcp = PortMappingProtocol::TCP;
+        pcp.add(tcp, 8080, local(loopback, 80), 3600, "")
+            .await
+            .unwrap();
+        pcp.add(tcp, 8080, local(loopback, 80), 3600, "")
+            .await
+            .unwrap();
+        assert_eq!(pcp.external_ip().await.unwrap(), IpAddr::V4(EXTERNAL_IP));
+        pcp.remove(tcp, 8080, local(loopback, 80)).await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        let [announce, add, renew, delete] = &requests[..] else {
+            unreachable!()
+        };
+        assert_eq!(announce[..2], [VERSION, OP_ANNOUNCE]);
+
+        assert_eq!(add[..2], [VERSION, OP_MAP]);
+        assert_eq!(add[4..8], 3600u32.to_be_bytes());
+        assert_eq!(add[8..24], Ipv4Addr::LOCALHOST.to_ipv6_mapped().octets());
+        let map = &add[HEADER_LEN..];
+        assert_eq!(map[12], 6);
+        assert_eq!(map[16..18], 80u16.to_be_bytes());
+        assert_eq!(map[18..20], 8080u16.to_be_bytes());
+
+        // Renewing and deleting must reuse the nonce.
+        assert_eq!(renew[HEADER_LEN..HEADER_LEN + 12], map[..12]);
+        assert_eq!(delete[HEADER_LEN..HEADER_LEN + 12], map[..12]);
+        assert_eq!(delete[4..8], 0u32.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn granted_lifetime_is_returned() {
+        let loopback = Ipv4Addr::LOCALHOST.into();
+        let server = Server::start(loopback, 0).await;
+        let pcp = Pcp::connect(server.addr).await.unwrap();
+
+        let tcp = PortMappingProtocol::TCP;
+        let granted = pcp.add(tcp, 8080, local(loopback, 80), 600, "").await;
+        assert_eq!(granted.unwrap(), 600);
+        let granted = pcp.add(tcp, 8080, local(loopback, 80), 0, "").await;
+        assert_eq!(granted.unwrap(), MAX_LIFETIME);
+    }
+
+    #[tokio::test]
+    async fn answers_to_other_mappings_are_skipped() {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let pcp = Pcp {
+            addr: socket.local_addr().unwrap(),
+            mappings: Default::default(),
+            external_ip: Default::default(),
+        };
+        tokio::spawn(async move {
+            let mut buffer = [0; 1100];
+            let (len, from) = socket.recv_from(&mut buffer).await.unwrap();
+            let mut response = buffer[..len].to_vec();
+            response[1] |= RESPONSE_BIT;
+            // Another nonce, protocol and internal port, then the right answer.
+            for at in [HEADER_LEN, HEADER_LEN + 12, HEADER_LEN + 17] {
+                let mut stray = response.clone();
+                stray[at] ^= 1;
+                stray[4..8].copy_from_slice(&1u32.to_be_bytes());
+                socket.send_to(&stray, from).await.unwrap();
+            }
+            socket.send_to(&response, from).await.unwrap();
+        });
+
+        let local = local(Ipv4Addr::LOCALHOST.into(), 80);
+        let granted = pcp
+            .add(PortMappingProtocol::TCP, 8080, local, 600, "")
+            .await;
+        assert_eq!(granted.unwrap(), 600);
+    }
+
+    #[tokio::test]
+    async fn separate_mappings_get_separate_nonces() {
+        let loopback = Ipv4Addr::LOCALHOST.into();
+        let server = Server::start(loopback, 0).await;
+        let pcp = Pcp::connect(server.addr).await.unwrap();
+
+        pcp.add(
+            PortMappingProtocol::TCP,
+            8080,
+            local(loopback, 80),
+            3600,
+            "",
+        )
+        .await
+        .unwrap();
+        pcp.add(
+            PortMappingProtocol::UDP,
+            8080,
+            local(loopback, 80),
+            3600,
+            "",
+        )
+        .await
+        .unwrap();
+
+        let requests = server.requests();
+        assert_ne!(
+            requests[1][HEADER_LEN..HEADER_LEN + 12],
+            requests[2][HEADER_LEN..HEADER_LEN + 12]
+        );
+        assert_eq!(requests[2][HEADER_LEN + 12], 17);
+    }
+
+    #[tokio::test]
+    async fn maps_ipv6_internal_address() {
+        let loopback = Ipv6Addr::LOCALHOST.into();
+        let server = Server::start(loopback, 0).await;
+        let pcp = Pcp::connect(server.addr).await.unwrap();
+
+        pcp.add(
+            PortMappingProtocol::UDP,
+            9000,
+            local(loopback, 9000),
+            600,
+            "",
+        )
+        .await
+        .unwrap();
+
+        let add = &server.requests()[1];
+        assert_eq!(add[8..24], Ipv6Addr::LOCALHOST.octets());
+        assert_eq!(add[HEADER_LEN + 12], 17);
+    }
+
+    #[tokio::test]
+    async fn third_party_mapping_uses_option() {
+        let loopback = Ipv4Addr::LOCALHOST.into();
+        let server = Server::start(loopback, 0).await;
+        let pcp = Pcp::connect(server.addr).await.unwrap();
+        let host = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 40));
+
+        pcp.add(PortMappingProtocol::TCP, 3074, local(host, 3074), 600, "")
+            .await
+            .unwrap();
+
+        let add = &server.requests()[1];
+        let option = &add[HEADER_LEN + MAP_LEN..];
+        assert_eq!(option[..4], [OPTION_THIRD_PARTY, 0, 0, 16]);
+        assert_eq!(option[4..20], ipv6_octets(host));
+    }
+
+    #[tokio::test]
+    async fn third_party_mappings_are_deleted_for_their_device() {
+        let loopback = Ipv4Addr::LOCALHOST.into();
+        let server = Server::start(loopback, 0).await;
+        let pcp = Pcp::connect(server.addr).await.unwrap();
+        // Two consoles with the same port, on different router ports.
+        let hosts = [
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 40)),
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 41)),
+        ];
+
+        let tcp = PortMappingProtocol::TCP;
+        pcp.add(tcp, 3074, local(hosts[0], 3074), 600, "")
+            .await
+            .unwrap();
+        pcp.add(tcp, 3075, local(hosts[1], 3074), 600, "")
+            .await
+            .unwrap();
+        pcp.remove(tcp, 3074, local(hosts[0], 3074)).await.unwrap();
+        pcp.remove(tcp, 3075, local(hosts[1], 3074)).await.unwrap();
+
+        let requests = server.requests();
+        let [_, add, other_add, delete, other_delete] = &requests[..] else {
+            panic!("expected 5 requests, got {}", requests.len());
+        };
+        assert_ne!(
+            add[HEADER_LEN..HEADER_LEN + 12],
+            other_add[HEADER_LEN..HEADER_LEN + 12]
+        );
+        for (add, delete, host) in [(add, delete, hosts[0]), (other_add, other_delete, hosts[1])] {
+            assert_eq!(delete[4..8], 0u32.to_be_bytes());
+            assert_eq!(
+                delete[HEADER_LEN..HEADER_LEN + 12],
+                add[HEADER_LEN..HEADER_LEN + 12]
+            );
+            let option = &delete[HEADER_LEN + MAP_LEN..];
+            assert_eq!(option[..4], [OPTION_THIRD_PARTY, 0, 0, 16]);
+            assert_eq!(option[4..20], ipv6_octets(host));
+        }
+    }
+
+    #[tokio::test]
+    async fn ipv6_server_refuses_other_devices_ipv4_address() {
+        let server = Server::start(Ipv6Addr::LOCALHOST.into(), 0).await;
+        let pcp = Pcp::connect(server.addr).await.unwrap();
+        let host = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 40));
+
+        let result = pcp
+            .add(PortMappingProtocol::TCP, 3074, local(host, 3074), 600, "")
+            .await;
+        assert!(matches!(
+            result,
+            Err(AddPortError::RequestError(RequestError::UnsupportedAction(
+                _
+            )))
+        ));
+        // Only the announcement reached the server.
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn refused_mapping_is_not_authorized() {
+        let loopback = Ipv4Addr::LOCALHOST.into();
+        let server = Server::start(loopback, RESULT_NOT_AUTHORIZED as u8).await;
+        let pcp = Pcp {
+            addr: server.addr,
+            mappings: Default::default(),
+            external_ip: Default::default(),
+        };
+
+        let result = pcp
+            .add(
+                PortMappingProtocol::TCP,
+                8080,
+                local(loopback, 80),
+                3600,
+                "",
+            )
+            .await;
+        assert!(matches!(result, Err(AddPortError::ActionNotAuthorized)));
+    }
+}
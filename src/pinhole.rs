@@ -0,0 +1,183 @@
+// src/pinhole.rs
+//! IPv6 firewall pinholes through an IGDv2 router's
+//! `WANIPv6FirewallControl:1` service. There is no NAT on IPv6, so a pinhole
+//! lets inbound traffic reach the device's own global address.
+
+use crate::gateway::{self, IgdGateway};
+use crate::wire::protocol_number;
+use igd::{AddPortError, PortMappingProtocol, RemovePortError, RequestError, SearchError};
+use std::collections::HashMap;
+use std::net::Ipv6Addr;
+use std::sync::{Arc, Mutex};
+use url::Url;
+use xmltree::Element;
+
+const FIREWALL_SERVICE: &str = "urn:schemas-upnp-org:service:WANIPv6FirewallControl:1";
+
+/// Longest lease a pinhole may have. There are no permanent pinholes.
+pub const MAX_PINHOLE_LEASE: u32 = 86400;
+
+const ERROR_NOT_AUTHORIZED: u16 = 606;
+const ERROR_PINHOLE_SPACE_EXHAUSTED: u16 = 701;
+const ERROR_NO_SUCH_ENTRY: u16 = 704;
+
+#[derive(Debug, Clone)]
+pub struct Firewall {
+    control_url: Url,
+    /// The open pinholes by protocol number and internal port.
+    pinholes: Arc<Mutex<HashMap<(u8, u16), Pinhole>>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Pinhole {
+    id: u16,
+    internal_ip: Ipv6Addr,
+}
+
+impl Firewall {
+    /// Looks up the firewall control service of `gateway`. Returns `None` if
+    /// the router doesn't offer one.
+    pub async fn find(gateway: &IgdGateway) -> Result<Option<Firewall>, SearchError> {
+        let root_url = Url::parse(&format!("http://{}{}", gateway.addr, gateway.root_url))
+            .map_err(|_| SearchError::InvalidResponse)?;
+        let root = Element::parse(&gateway::get(&root_url).await?[..])?;
+        let Some((_, control_url)) = gateway::find_service(&root, &[FIREWALL_SERVICE]) else {
+            return Ok(None);
+        };
+        Ok(Some(Firewall {
+            control_url: gateway::join(&root_url, &control_url)?,
+            pinholes: Default::default(),
+        }))
+    }
+
+    /// Opens `internal_port` on `internal_ip` to any remote host, or renews
+    /// the pinhole if it is open already. A pinhole for an old address is
+    /// replaced.
+    pub async fn add_or_update(
+        &self,
+        protocol: PortMappingProtocol,
+        internal_ip: Ipv6Addr,
+        internal_port: u16,
+        lease_time: u32,
+    ) -> Result<(), AddPortError> {
+        let key = (protocol_number(protocol), internal_port);
+        let lease_time = lease_time.to_string();
+        let open = self.pinholes.lock().unwrap().get(&key).copied();
+
+        match open {
+            Some(pinhole) if pinhole.internal_ip == internal_ip => {
+                let id = pinhole.id.to_string();
+                let args = [("UniqueID", id.as_str()), ("NewLeaseTime", &lease_time)];
+                match self.call("UpdatePinhole", &args).await {
+                    // The router forgot it, e.g. after a reboot.
+                    Err(RequestError::ErrorCode(ERROR_NO_SUCH_ENTRY, _)) => {}
+                    result => return result.map(|_| ()).map_err(add_error),
+                }
+            }
+            Some(_) => {
+                // The device's address changed.
+                let _ = self.remove(protocol, internal_port).await;
+            }
+            None => {}
+        }
+
+        let internal_client = internal_ip.to_string();
+        let internal_port_text = internal_port.to_string();
+        let protocol_text = key.0.to_string();
+        let args = [
+            ("RemoteHost", ""),
+            ("RemotePort", "0"),
+            ("InternalClient", internal_client.as_str()),
+            ("InternalPort", &internal_port_text),
+            ("Protocol", &protocol_text),
+            ("LeaseTime", &lease_time),
+        ];
+        let response = self.call("AddPinhole", &args).await.map_err(add_error)?;
+        let id = gateway::text_of(&response, "UniqueID")
+            .and_then(|id| id.parse().ok())
+            .ok_or_else(|| {
+                AddPortError::RequestError(RequestError::InvalidResponse(
+                    "AddPinhole returned no UniqueID".to_string(),
+                ))
+            })?;
+        self.pinholes
+            .lock()
+            .unwrap()
+            .insert(key, Pinhole { id, internal_ip });
+        Ok(())
+    }
+
+    /// Closes the pinhole of `internal_port` opened by `add_or_update`.
+    pub async fn remove(
+        &self,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+    ) -> Result<(), RemovePortError> {
+        let key = (protocol_number(protocol), internal_port);
+        let Some(pinhole) = self.pinholes.lock().unwrap().remove(&key) else {
+            return Err(RemovePortError::NoSuchPortMapping);
+        };
+        let id = pinhole.id.to_string();
+        match self.call("DeletePinhole", &[("UniqueID", &id)]).await {
+            Ok(_) => Ok(()),
+            Err(RequestError::ErrorCode(ERROR_NOT_AUTHORIZED, _)) => {
+                Err(RemovePortError::ActionNotAuthorized)
+            }
+            Err(RequestError::ErrorCode(ERROR_NO_SUCH_ENTRY, _)) => {
+                Err(RemovePortError::NoSuchPortMapping)
+            }
+            Err(e) => Err(RemovePortError::RequestError(e)),
+        }
+    }
+
+    /// Calls `action` on the firewall service and returns the response
+    /// element, or the UPnP error as `RequestError::ErrorCode`.
+    async fn call(&self, action: &str, args: &[(&str, &str)]) -> Result<Element, RequestError> {
+        let args: String = args
+            .iter()
+            .map(|(name, value)| format!("<{0}>{1}</{0}>", name, value))
+            .collect();
+        let body = format!(
+            "<?xml version=\"1.0\"?>\
+            <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+            s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+            <s:Body><u:{action} xmlns:u=\"{service}\">{args}</u:{action}></s:Body>\
+            </s:Envelope>",
+            action = action,
+            service = FIREWALL_SERVICE,
+            args = args
+        );
+        let soap_action = format!("\"{}#{}\"", FIREWALL_SERVICE, action);
+        let headers = [
+            ("Content-Type", "text/xml; charset=\"utf-8\""),
+            ("SOAPAction", soap_action.as_str()),
+        ];
+        let (_, response) = gateway::http(&self.control_url, "POST", &headers, &body).await?;
+
+        let invalid = || RequestError::InvalidResponse(String::from_utf8_lossy(&response).into());
+        let envelope = Element::parse(&response[..]).map_err(|_| invalid())?;
+        let body = envelope.get_child("Body").ok_or_else(invalid)?;
+        if let Some(error) = body
+            .get_child("Fault")
+            .and_then(|fault| fault.get_child("detail"))
+            .and_then(|detail| detail.get_child("UPnPError"))
+        {
+            let code = gateway::text_of(error, "errorCode")
+                .and_then(|code| code.parse().ok())
+                .ok_or_else(invalid)?;
+            let description = gateway::text_of(error, "errorDescription").unwrap_or_default();
+            return Err(RequestError::ErrorCode(code, description));
+        }
+        body.get_child(format!("{}Response", action).as_str())
+            .cloned()
+            .ok_or_else(invalid)
+    }
+}
+
+fn add_error(e: RequestError) -> AddPortError {
+    match e {
+        RequestError::ErrorCode(ERROR_NOT_AUTHORIZED, _) => AddPortError::ActionNotAuthorized,
+        RequestError::ErrorCode(ERROR_PINHOLE_SPACE_EXHAUSTED, _) => AddPortError::PortInUse,
+        e => AddPortError::RequestError(e),
+    }
+}
@@ -1,2 +1,43 @@
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+#[cfg(unix)]
+pub(crate) mod unix;
 #[cfg(windows)]
-pub(crate) mod windows;
\ No newline at end of file
+pub(crate) mod windows;
+
+/// How long the program gets to shut down on its own after `callback` ran,
+/// before the termination handler exits the process.
+const FORCED_EXIT_AFTER: Duration = Duration::from_secs(8);
+
+/// Runs `callback` when the process is asked to terminate. The callback should
+/// only request a shutdown; the program is expected to clean up and exit on
+/// its own within `FORCED_EXIT_AFTER`.
+///
+/// On Windows this covers Ctrl+C and closing the console window, on Unix
+/// SIGINT, SIGTERM and SIGHUP.
+pub fn register_termination_handler<F>(callback: F)
+where
+    F: Fn() + Send + 'static,
+{
+    #[cfg(windows)]
+    windows::register_windows_console_ctrl_handler(callback);
+    #[cfg(unix)]
+    unix::register_unix_signal_handler(callback);
+}
+
+/// The router of the default IPv4 route, if the system has one. It is looked
+/// up on a blocking thread, since that may run the `route` command.
+pub async fn default_gateway() -> Option<Ipv4Addr> {
+    tokio::task::spawn_blocking(system_default_gateway)
+        .await
+        .ok()
+        .flatten()
+}
+
+fn system_default_gateway() -> Option<Ipv4Addr> {
+    #[cfg(windows)]
+    return windows::default_gateway();
+    #[cfg(unix)]
+    return unix::default_gateway();
+}
@@ -0,0 +1,71 @@
+use super::FORCED_EXIT_AFTER;
+use std::net::Ipv4Addr;
+use std::process;
+use std::thread;
+use tokio::runtime::Builder;
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Waits for SIGINT, SIGTERM or SIGHUP on a dedicated thread, then runs
+/// `callback`, like the Windows console control handler.
+pub fn register_unix_signal_handler<F>(callback: F)
+where
+    F: Fn() + Send + 'static,
+{
+    // Install the handlers before returning, so no signal is missed.
+    let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+    let mut signals = {
+        let _guard = runtime.enter();
+        [
+            SignalKind::interrupt(),
+            SignalKind::terminate(),
+            SignalKind::hangup(),
+        ]
+        .map(|kind| signal(kind).expect("Error setting up signal handler"))
+    };
+
+    thread::spawn(move || {
+        runtime.block_on(async {
+            let [interrupt, terminate, hangup] = &mut signals;
+            tokio::select! {
+                _ = interrupt.recv() => {}
+                _ = terminate.recv() => {}
+                _ = hangup.recv() => {}
+            }
+        });
+
+        // Outside the runtime, so the callback may start its own.
+        callback();
+        thread::sleep(FORCED_EXIT_AFTER);
+        process::exit(1);
+    });
+}
+
+/// Reads the default route from the kernel's routing table.
+#[cfg(target_os = "linux")]
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    const RTF_GATEWAY: u32 = 0x2;
+
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    routes.lines().skip(1).find_map(|line| {
+        // Iface Destination Gateway Flags ..., addresses in hex as stored in memory
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let flags = u32::from_str_radix(fields.get(3)?, 16).ok()?;
+        if *fields.get(1)? != "00000000" || flags & RTF_GATEWAY == 0 {
+            return None;
+        }
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_ne_bytes()))
+    })
+}
+
+/// Asks `route` for the default route, as on macOS and the BSDs.
+#[cfg(not(target_os = "linux"))]
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    let output = process::Command::new("route")
+        .args(["-n", "get", "default"])
+        .output()
+        .ok()?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("gateway:")?.trim().parse().ok())
+}
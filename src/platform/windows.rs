@@ -1,4 +1,7 @@
+use super::FORCED_EXIT_AFTER;
+use std::net::Ipv4Addr;
 use std::process;
+use std::thread;
 use winapi::shared::minwindef::{BOOL, TRUE};
 use winapi::um::consoleapi::SetConsoleCtrlHandler;
 
@@ -27,7 +30,10 @@ unsafe extern "system" fn ctrl_handler(_ctrl_type: u32) -> BOOL {
             callback();
         }
     }
-    process::exit(0);
+    // Returning lets Windows terminate the process, so wait for the program to
+    // clean up and exit on its own.
+    thread::sleep(FORCED_EXIT_AFTER);
+    process::exit(1);
     // TRUE
 }
 
@@ -45,3 +51,20 @@ where
         }
     }
 }
+
+/// Reads the default route from `route print`.
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    let output = process::Command::new("route")
+        .args(["print", "-4", "0.0.0.0"])
+        .output()
+        .ok()?;
+    // Network Destination, Netmask, Gateway, Interface, Metric
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(
+            |line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+                ["0.0.0.0", "0.0.0.0", gateway, ..] => gateway.parse().ok(),
+                _ => None,
+            },
+        )
+}
@@ -0,0 +1,500 @@
+// src/test_support.rs
+//! A UPnP IGD stand-in for tests: an SSDP responder, a `WANIPConnection:1`
+//! service and a `WANIPv6FirewallControl:1` service on loopback, keeping
+//! mapping and pinhole tables like a router does. Failures can be injected
+//! per action.
+
+use igd::PortMappingProtocol;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::future;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::time::{self, Duration};
+use xmltree::Element;
+
+pub const EXTERNAL_IP: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 9);
+
+/// The action SSDP search requests are recorded as, so they can fail too.
+pub const SEARCH: &str = "M-SEARCH";
+
+const SERVICE: &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+const FIREWALL_SERVICE: &str = "urn:schemas-upnp-org:service:WANIPv6FirewallControl:1";
+
+const ROOT_DESCRIPTION: &str = "\
+<?xml version=\"1.0\"?>\
+<root xmlns=\"urn:schemas-upnp-org:device-1-0\"><device>\
+<deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>\
+<deviceList><device>\
+<deviceType>urn:schemas-upnp-org:device:WANDevice:1</deviceType>\
+<deviceList><device>\
+<deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:1</deviceType>\
+<serviceList><service>\
+<serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+<controlURL>/ctl/IPConn</controlURL>\
+<SCPDURL>/WANIPCn.xml</SCPDURL>\
+</service><service>\
+<serviceType>urn:schemas-upnp-org:service:WANIPv6FirewallControl:1</serviceType>\
+<controlURL>/ctl/IP6FCtl</controlURL>\
+<SCPDURL>/WANIP6FC.xml</SCPDURL>\
+</service></serviceList>\
+</device></deviceList>\
+</device></deviceList>\
+</device></root>";
+
+/// Actions of the `WANIPConnection:1` service with their input arguments.
+const ACTIONS: &[(&str, &[&str])] = &[
+    (
+        "AddPortMapping",
+        &[
+            "NewRemoteHost",
+            "NewExternalPort",
+            "NewProtocol",
+            "NewInternalPort",
+            "NewInternalClient",
+            "NewEnabled",
+            "NewPortMappingDescription",
+            "NewLeaseDuration",
+        ],
+    ),
+    (
+        "DeletePortMapping",
+        &["NewRemoteHost", "NewExternalPort", "NewProtocol"],
+    ),
+    ("GetExternalIPAddress", &[]),
+    ("GetGenericPortMappingEntry", &["NewPortMappingIndex"]),
+];
+
+const ERROR_INVALID_ACTION: u16 = 401;
+const ERROR_INVALID_ARGS: u16 = 402;
+const ERROR_INVALID_INDEX: u16 = 713;
+const ERROR_NO_SUCH_ENTRY: u16 = 714;
+const ERROR_CONFLICT: u16 = 718;
+const ERROR_ONLY_PERMANENT_LEASES: u16 = 725;
+const ERROR_NO_SUCH_PINHOLE: u16 = 704;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Failure {
+    /// Answers with this UPnP error code, e.g. 718 or 725.
+    Error(u16),
+    /// Never answers, keeping the connection open.
+    Timeout,
+    /// Closes the connection without answering, as a rebooting router does.
+    Disconnect,
+}
+
+/// A mapping in the router's table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub internal_client: Ipv4Addr,
+    pub internal_port: u16,
+    pub lease_duration: u32,
+    pub description: String,
+}
+
+/// An open pinhole in the router's IPv6 firewall.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pinhole {
+    pub internal_client: Ipv6Addr,
+    pub internal_port: u16,
+    /// The IANA protocol number, 6 for TCP and 17 for UDP.
+    pub protocol: u8,
+    pub lease_time: u32,
+}
+
+#[derive(Default)]
+struct State {
+    /// Mappings by protocol name and external port.
+    mappings: BTreeMap<(String, u16), Entry>,
+    /// Pinholes by their unique ID.
+    pinholes: BTreeMap<u16, Pinhole>,
+    next_pinhole_id: u16,
+    /// Every action called, in order.
+    calls: Vec<String>,
+    /// Failures to inject by action, one per call. `None` lets a call through.
+    failures: HashMap<String, VecDeque<Option<Failure>>>,
+}
+
+impl State {
+    /// Records a call of `action` and takes the failure injected for it.
+    fn call(&mut self, action: &str) -> Option<Failure> {
+        self.calls.push(action.to_string());
+        self.failures.get_mut(action)?.pop_front().flatten()
+    }
+}
+
+pub struct Igd {
+    /// Where to send SSDP search requests.
+    pub ssdp_addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl Igd {
+    pub async fn start() -> Igd {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let root_url = format!("http://{}/rootDesc.xml", listener.local_addr().unwrap());
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let ssdp_addr = socket.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let ssdp_state = state.clone();
+        tokio::spawn(async move {
+            let mut buffer = [0; 1500];
+            loop {
+                let (len, from) = socket.recv_from(&mut buffer).await.unwrap();
+                if !buffer[..len].starts_with(SEARCH.as_bytes()) {
+                    continue;
+                }
+                // A failed search gets no answer at all.
+                if ssdp_state.lock().unwrap().call(SEARCH).is_some() {
+                    continue;
+                }
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\n\
+                    CACHE-CONTROL: max-age=120\r\n\
+                    ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+                    USN: uuid:00000000-0000-0000-0000-000000000000::\
+                    urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+                    LOCATION: {}\r\n\r\n",
+                    root_url
+                );
+                let _ = socket.send_to(response.as_bytes(), from).await;
+            }
+        });
+
+        let http_state = state.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(serve(stream, http_state.clone()));
+            }
+        });
+
+        Igd { ssdp_addr, state }
+    }
+
+    /// Makes the next call of `action` fail. Failures queue up.
+    pub fn fail(&self, action: &str, failure: Failure) {
+        self.queue(action, Some(failure));
+    }
+
+    /// Lets the next call of `action` through, so a failure queued after it
+    /// hits a later call.
+    pub fn pass(&self, action: &str) {
+        self.queue(action, None);
+    }
+
+    fn queue(&self, action: &str, failure: Option<Failure>) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .failures
+            .entry(action.to_string())
+            .or_default()
+            .push_back(failure);
+    }
+
+    /// Adds a mapping to the table, e.g. another device's.
+    pub fn insert(&self, protocol: PortMappingProtocol, external_port: u16, entry: Entry) {
+        let key = (protocol.to_string(), external_port);
+        self.state.lock().unwrap().mappings.insert(key, entry);
+    }
+
+    pub fn mapping(&self, protocol: PortMappingProtocol, external_port: u16) -> Option<Entry> {
+        let key = (protocol.to_string(), external_port);
+        self.state.lock().unwrap().mappings.get(&key).cloned()
+    }
+
+    /// The open pinholes, ordered by ID.
+    pub fn pinholes(&self) -> Vec<Pinhole> {
+        let state = self.state.lock().unwrap();
+        state.pinholes.values().cloned().collect()
+    }
+
+    /// Closes every pinhole, as a rebooting router does.
+    pub fn forget_pinholes(&self) {
+        self.state.lock().unwrap().pinholes.clear();
+    }
+
+    pub fn calls(&self) -> Vec<String> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    /// How often `action` was called.
+    pub fn count(&self, action: &str) -> usize {
+        self.calls().iter().filter(|call| *call == action).count()
+    }
+
+    /// Waits until `action` was called `count` times. Panics after 10s.
+    pub async fn wait_for(&self, action: &str, count: usize) {
+        let wait = async {
+            while self.count(action) < count {
+                time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        if time::timeout(Duration::from_secs(10), wait).await.is_err() {
+            panic!("{} was called {} times", action, self.count(action));
+        }
+    }
+}
+
+/// Answers one HTTP request: the descriptions, or a SOAP call.
+async fn serve(mut stream: TcpStream, state: Arc<Mutex<State>>) {
+    let Some((head, body)) = read_request(&mut stream).await else {
+        return;
+    };
+    let mut request_line = head.split_whitespace();
+    let (method, path) = (request_line.next(), request_line.next());
+
+    let (status, response) = match (method, path) {
+        (Some("GET"), Some("/rootDesc.xml")) => (200, ROOT_DESCRIPTION.to_string()),
+        (Some("GET"), Some("/WANIPCn.xml")) => (200, scpd()),
+        (Some("POST"), Some(path @ ("/ctl/IPConn" | "/ctl/IP6FCtl"))) => {
+            let service = if path == "/ctl/IPConn" {
+                SERVICE
+            } else {
+                FIREWALL_SERVICE
+            };
+            let Some((action, args)) = parse_call(&body) else {
+                return respond(&mut stream, 500, &fault(ERROR_INVALID_ACTION)).await;
+            };
+            let failure = state.lock().unwrap().call(&action);
+            let result = match failure {
+                Some(Failure::Error(code)) => Err(code),
+                Some(Failure::Timeout) => return future::pending().await,
+                Some(Failure::Disconnect) => return,
+                None => perform(&mut state.lock().unwrap(), &action, &args),
+            };
+            match result {
+                Ok(values) => (
+                    200,
+                    envelope(&format!(
+                        "<u:{0}Response xmlns:u=\"{1}\">{2}</u:{0}Response>",
+                        action, service, values
+                    )),
+                ),
+                Err(code) => (500, fault(code)),
+            }
+        }
+        _ => (404, String::new()),
+    };
+    respond(&mut stream, status, &response).await;
+}
+
+/// Reads the request head and a body of `Content-Length` bytes.
+async fn read_request(stream: &mut TcpStream) -> Option<(String, String)> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 4096];
+    loop {
+        if let Some(body_at) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&request[..body_at]).into_owned();
+            let len: usize = head
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                .and_then(|(_, value)| value.trim().parse().ok())
+                .unwrap_or(0);
+            if request.len() >= body_at + 4 + len {
+                let body = &request[body_at + 4..body_at + 4 + len];
+                return Some((head, String::from_utf8_lossy(body).into_owned()));
+            }
+        }
+        let read = stream.read(&mut buffer).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+}
+
+async fn respond(stream: &mut TcpStream, status: u16, body: &str) {
+    let response = format!(
+        "HTTP/1.1 {} {}\r\n\
+        Content-Type: text/xml; charset=\"utf-8\"\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\r\n{}",
+        status,
+        if status == 200 { "OK" } else { "Error" },
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+/// The action of a SOAP request and its arguments.
+fn parse_call(body: &str) -> Option<(String, HashMap<String, String>)> {
+    let envelope = Element::parse(body.as_bytes()).ok()?;
+    let call = envelope
+        .get_child("Body")?
+        .children
+        .iter()
+        .find_map(|child| child.as_element())?;
+    let args = call
+        .children
+        .iter()
+        .filter_map(|child| child.as_element())
+        .map(|arg| {
+            let value = arg.get_text().unwrap_or_default().trim().to_string();
+            (arg.name.clone(), value)
+        })
+        .collect();
+    Some((call.name.clone(), args))
+}
+
+/// Carries out `action` on the table. Returns the output arguments as XML, or
+/// a UPnP error code.
+fn perform(state: &mut State, action: &str, args: &HashMap<String, String>) -> Result<String, u16> {
+    let arg = |name: &str| args.get(name).ok_or(ERROR_INVALID_ARGS);
+    let number = |name: &str| arg(name)?.parse::<u32>().map_err(|_| ERROR_INVALID_ARGS);
+
+    match action {
+        "AddPortMapping" => {
+            let key = (
+                arg("NewProtocol")?.clone(),
+                number("NewExternalPort")? as u16,
+            );
+            let entry = Entry {
+                internal_client: arg("NewInternalClient")?
+                    .parse()
+                    .map_err(|_| ERROR_INVALID_ARGS)?,
+                internal_port: number("NewInternalPort")? as u16,
+                lease_duration: number("NewLeaseDuration")?,
+                description: arg("NewPortMappingDescription")?.clone(),
+            };
+            // Only the device owning a mapping may change it.
+            match state.mappings.get(&key) {
+                Some(old) if old.internal_client != entry.internal_client => Err(ERROR_CONFLICT),
+                _ => {
+                    state.mappings.insert(key, entry);
+                    Ok(String::new())
+                }
+            }
+        }
+        "DeletePortMapping" => {
+            let key = (
+                arg("NewProtocol")?.clone(),
+                number("NewExternalPort")? as u16,
+            );
+            match state.mappings.remove(&key) {
+                Some(_) => Ok(String::new()),
+                None => Err(ERROR_NO_SUCH_ENTRY),
+            }
+        }
+        "GetExternalIPAddress" => Ok(format!(
+            "<NewExternalIPAddress>{}</NewExternalIPAddress>",
+            EXTERNAL_IP
+        )),
+        "GetGenericPortMappingEntry" => {
+            let index = number("NewPortMappingIndex")? as usize;
+            let ((protocol, external_port), entry) = state
+                .mappings
+                .iter()
+                .nth(index)
+                .ok_or(ERROR_INVALID_INDEX)?;
+            Ok(format!(
+                "<NewRemoteHost></NewRemoteHost>\
+                <NewExternalPort>{}</NewExternalPort>\
+                <NewProtocol>{}</NewProtocol>\
+                <NewInternalPort>{}</NewInternalPort>\
+                <NewInternalClient>{}</NewInternalClient>\
+                <NewEnabled>1</NewEnabled>\
+                <NewPortMappingDescription>{}</NewPortMappingDescription>\
+                <NewLeaseDuration>{}</NewLeaseDuration>",
+                external_port,
+                protocol,
+                entry.internal_port,
+                entry.internal_client,
+                entry.description,
+                entry.lease_duration
+            ))
+        }
+        "AddPinhole" => {
+            let pinhole = Pinhole {
+                internal_client: arg("InternalClient")?
+                    .parse()
+                    .map_err(|_| ERROR_INVALID_ARGS)?,
+                internal_port: number("InternalPort")? as u16,
+                protocol: number("Protocol")? as u8,
+                lease_time: number("LeaseTime")?,
+            };
+            state.next_pinhole_id += 1;
+            let id = state.next_pinhole_id;
+            state.pinholes.insert(id, pinhole);
+            Ok(format!("<UniqueID>{}</UniqueID>", id))
+        }
+        "UpdatePinhole" => {
+            let id = number("UniqueID")? as u16;
+            let lease_time = number("NewLeaseTime")?;
+            let pinhole = state.pinholes.get_mut(&id).ok_or(ERROR_NO_SUCH_PINHOLE)?;
+            pinhole.lease_time = lease_time;
+            Ok(String::new())
+        }
+        "DeletePinhole" => {
+            let id = number("UniqueID")? as u16;
+            match state.pinholes.remove(&id) {
+                Some(_) => Ok(String::new()),
+                None => Err(ERROR_NO_SUCH_PINHOLE),
+            }
+        }
+        _ => Err(ERROR_INVALID_ACTION),
+    }
+}
+
+fn scpd() -> String {
+    let actions: String = ACTIONS
+        .iter()
+        .map(|(name, arguments)| {
+            let arguments: String = arguments
+                .iter()
+                .map(|argument| {
+                    format!(
+                        "<argument><name>{}</name><direction>in</direction></argument>",
+                        argument
+                    )
+                })
+                .collect();
+            format!(
+                "<action><name>{}</name><argumentList>{}</argumentList></action>",
+                name, arguments
+            )
+        })
+        .collect();
+    format!(
+        "<?xml version=\"1.0\"?>\
+        <scpd xmlns=\"urn:schemas-upnp-org:service-1-0\">\
+        <actionList>{}</actionList></scpd>",
+        actions
+    )
+}
+
+fn envelope(body: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\"?>\
+        <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+        s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+        <s:Body>{}</s:Body></s:Envelope>",
+        body
+    )
+}
+
+fn fault(code: u16) -> String {
+    // igd rejects a fault without a description.
+    let description = match code {
+        ERROR_INVALID_ACTION => "Invalid Action",
+        ERROR_INVALID_ARGS => "Invalid Args",
+        ERROR_INVALID_INDEX => "SpecifiedArrayIndexInvalid",
+        ERROR_NO_SUCH_ENTRY => "NoSuchEntryInArray",
+        ERROR_CONFLICT => "ConflictInMappingEntry",
+        ERROR_ONLY_PERMANENT_LEASES => "OnlyPermanentLeasesSupported",
+        ERROR_NO_SUCH_PINHOLE => "NoSuchEntry",
+        _ => "Action Failed",
+    };
+    envelope(&format!(
+        "<s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring>\
+        <detail><UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\">\
+        <errorCode>{}</errorCode><errorDescription>{}</errorDescription>\
+        </UPnPError></detail></s:Fault>",
+        code, description
+    ))
+}
@@ -0,0 +1,54 @@
+// src/wire.rs
+//! Pieces shared by the protocols: the UDP request loop of NAT-PMP and PCP,
+//! and the IANA protocol numbers PCP and IPv6 pinholes use.
+
+use igd::{PortMappingProtocol, RequestError};
+use std::io;
+use tokio::net::UdpSocket;
+use tokio::time::{self, Duration};
+
+/// The first request waits this long for an answer, and every retry twice as
+/// long (RFC 6886 section 3.1, kept by RFC 6887).
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// RFC 6886 allows nine attempts, about two minutes. Four keep a request
+/// within the cleanup timeout.
+const ATTEMPTS: u32 = 4;
+
+/// Largest PCP message (RFC 6887 section 7). NAT-PMP ones are shorter.
+const MAX_MESSAGE_LEN: usize = 1100;
+
+/// Sends `packet` to the `server` the socket is connected to until `parse`
+/// accepts a response. `parse` returns `None` for stray packets, e.g. answers
+/// to an earlier attempt's retry, which are skipped.
+pub async fn request<T>(
+    socket: &UdpSocket,
+    packet: &[u8],
+    server: &str,
+    parse: impl Fn(&[u8]) -> Option<Result<T, RequestError>>,
+) -> Result<T, RequestError> {
+    let mut buffer = [0; MAX_MESSAGE_LEN];
+    let mut timeout = INITIAL_TIMEOUT;
+    for _ in 0..ATTEMPTS {
+        socket.send(packet).await?;
+        let deadline = time::Instant::now() + timeout;
+        while let Ok(received) = time::timeout_at(deadline, socket.recv(&mut buffer)).await {
+            if let Some(result) = parse(&buffer[..received?]) {
+                return result;
+            }
+        }
+        timeout *= 2;
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("no answer from {}", server),
+    )
+    .into())
+}
+
+pub fn protocol_number(protocol: PortMappingProtocol) -> u8 {
+    match protocol {
+        PortMappingProtocol::TCP => 6,
+        PortMappingProtocol::UDP => 17,
+    }
+}